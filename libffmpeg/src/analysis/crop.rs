@@ -0,0 +1,252 @@
+use std::{cmp::Reverse, collections::HashMap, fmt, path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use super::{AnalysisError, job_options};
+use crate::{duration::get_duration, ffmpeg::ffmpeg, log::LogLevel};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct CropDetectOptions {
+    /// Number of evenly spaced segments sampled
+    pub samples: usize,
+    /// Length of each sampled segment
+    pub sample_duration: Duration,
+    /// `limit`, highest luma value still considered black
+    pub limit: u32,
+    /// `round`, width and height are rounded down to a multiple of this
+    pub round: u32,
+    /// Samples whose suggestion covers less than this fraction of the largest suggestion's area are
+    /// treated as fades or dark scenes and ignored
+    pub min_area_ratio: f64,
+    /// Stream analysed, as passed to `-map`
+    #[builder(setter(into))]
+    pub video_stream: String,
+    /// Longest each sample run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during each sample run, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for CropDetectOptions {
+    fn default() -> Self {
+        Self {
+            samples: 10,
+            sample_duration: Duration::from_secs(2),
+            limit: 24,
+            round: 2,
+            min_area_ratio: 0.5,
+            video_stream: "0:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+/// A crop rectangle, `Display`s as a `crop` filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Valuable)]
+pub struct CropRect {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl CropRect {
+    #[must_use]
+    pub fn area(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+}
+
+impl fmt::Display for CropRect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "crop={}:{}:{}:{}",
+            self.width, self.height, self.x, self.y
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CropDetection {
+    /// `None` when no sample produced a usable suggestion (e.g. an entirely black input)
+    pub crop: Option<CropRect>,
+    /// The most common suggestion of each sample, in sample order
+    pub sample_crops: Vec<Option<CropRect>>,
+}
+
+/// Suggest a crop removing letterboxing/pillarboxing.
+///
+/// Runs `cropdetect` over `options.samples` segments spread evenly through the input (seeking to each
+/// one), takes the most common suggestion of each, drops samples that look like fades, then picks the
+/// most common remaining suggestion, preferring the larger crop on ties.
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn detect_crop<P: AsRef<Path>>(
+    input: P,
+    options: &CropDetectOptions,
+    cancellation_token: CancellationToken,
+) -> Result<CropDetection, AnalysisError> {
+    let input = input.as_ref();
+    let duration = get_duration(input, cancellation_token.clone()).await?;
+    tracing::debug!(duration = ?duration, samples = options.samples, "Starting crop detection");
+
+    let filter = format!(
+        "cropdetect=limit={}:round={}:reset=0",
+        options.limit, options.round
+    );
+
+    // cropdetect logs at info
+    let job_options = job_options(LogLevel::Info, options.timeout, options.stall_timeout);
+    let mut sample_crops = Vec::with_capacity(options.samples);
+    for index in 0..options.samples {
+        let offset = duration.mul_f64((index + 1) as f64 / (options.samples + 1) as f64);
+        let result = ffmpeg(&job_options, cancellation_token.clone(), |cmd| {
+            cmd.arg("-nostats");
+            cmd.arg("-ss").arg(format!("{:.3}", offset.as_secs_f64()));
+            cmd.arg("-t")
+                .arg(format!("{:.3}", options.sample_duration.as_secs_f64()));
+            cmd.arg("-i").arg(input);
+            cmd.arg("-map").arg(&options.video_stream);
+            cmd.arg("-filter:v").arg(&filter);
+            cmd.arg("-an").arg("-f").arg("null").arg("-");
+        })
+        .await?;
+
+        let crop = mode(
+            result
+                .stderr_lines
+                .iter()
+                .filter_map(|line| parse_cropdetect_line(line)),
+        );
+        tracing::debug!(offset = ?offset, crop = ?crop, "Sampled crop");
+        sample_crops.push(crop);
+    }
+
+    let max_area = sample_crops
+        .iter()
+        .flatten()
+        .map(CropRect::area)
+        .max()
+        .unwrap_or_default();
+    let crop = mode(
+        sample_crops
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|crop| crop.area() as f64 >= max_area as f64 * options.min_area_ratio),
+    );
+
+    tracing::info!(crop = ?crop, "Crop detection complete");
+
+    Ok(CropDetection { crop, sample_crops })
+}
+
+/// Most common rect, ties broken by the larger area and then by the rect seen first
+fn mode(crops: impl Iterator<Item = CropRect>) -> Option<CropRect> {
+    // Count and index of the first sample, per rect
+    let mut counts = HashMap::<CropRect, (usize, usize)>::new();
+    for (index, crop) in crops.enumerate() {
+        counts.entry(crop).or_insert((0, index)).0 += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(crop, (count, first))| (*count, crop.area(), Reverse(*first)))
+        .map(|(crop, _)| crop)
+}
+
+/// Parse the trailing `crop=w:h:x:y` of a cropdetect log line, ignoring degenerate suggestions
+pub(crate) fn parse_cropdetect_line(line: &str) -> Option<CropRect> {
+    let (_, crop) = line.rsplit_once("crop=")?;
+    let mut values = crop
+        .split_whitespace()
+        .next()?
+        .split(':')
+        .map(|value| value.parse::<i64>().ok());
+    let width = values.next()??;
+    let height = values.next()??;
+    let x = values.next()??;
+    let y = values.next()??;
+
+    Some(CropRect {
+        width: u32::try_from(width).ok().filter(|w| *w > 0)?,
+        height: u32::try_from(height).ok().filter(|h| *h > 0)?,
+        x: u32::try_from(x).ok()?,
+        y: u32::try_from(y).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LETTERBOXED: &str = "[Parsed_cropdetect_0 @ 0x55d0c6f0a2c0] x1:0 x2:1919 y1:138 y2:941 w:1920 h:800 x:0 y:140 pts:1001 t:0.041708 limit:0.094118 crop=1920:800:0:140";
+    const BLACK: &str = "[Parsed_cropdetect_0 @ 0x55d0c6f0a2c0] x1:1919 x2:0 y1:1079 y2:0 w:-1904 h:-1064 x:1912 y:1072 pts:0 t:0.000000 limit:0.094118 crop=-1904:-1064:1912:1072";
+
+    #[test]
+    fn parses_crop_suggestion() {
+        let crop = parse_cropdetect_line(LETTERBOXED).unwrap();
+        assert_eq!(
+            crop,
+            CropRect {
+                width: 1920,
+                height: 800,
+                x: 0,
+                y: 140,
+            }
+        );
+        assert_eq!(crop.to_string(), "crop=1920:800:0:140");
+    }
+
+    #[test]
+    fn ignores_black_frame_suggestion() {
+        assert_eq!(parse_cropdetect_line(BLACK), None);
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert_eq!(
+            parse_cropdetect_line(
+                "Stream #0:0: Video: h264 (High), yuv420p(progressive), 1920x1080, 23.98 fps"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn mode_prefers_larger_crop_on_ties() {
+        let wide = CropRect {
+            width: 1920,
+            height: 800,
+            x: 0,
+            y: 140,
+        };
+        let narrow = CropRect {
+            width: 1440,
+            height: 800,
+            x: 240,
+            y: 140,
+        };
+        assert_eq!(mode([narrow, wide].into_iter()), Some(wide));
+        assert_eq!(mode([narrow, wide, narrow].into_iter()), Some(narrow));
+        assert_eq!(mode(std::iter::empty()), None);
+
+        // Same count and area, the first one seen wins whatever the map's order
+        let shifted = CropRect { y: 141, ..wide };
+        assert_eq!(mode([wide, shifted].into_iter()), Some(wide));
+        assert_eq!(mode([shifted, wide].into_iter()), Some(shifted));
+        assert_eq!(
+            mode([narrow, shifted, wide, wide, shifted].into_iter()),
+            Some(shifted)
+        );
+    }
+}
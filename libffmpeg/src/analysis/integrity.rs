@@ -0,0 +1,367 @@
+use std::{path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use super::{AnalysisError, job_options};
+use crate::{
+    ffmpeg::{FfmpegProgress, ffmpeg_monitored, send_progress},
+    log::{LogLevel, split_log_prefixes},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct IntegrityOptions {
+    /// Streams decoded, as passed to `-map`
+    #[builder(setter(into))]
+    pub streams: String,
+    /// `-err_detect` for the input, e.g. `crccheck+bitstream` for stricter checks
+    #[builder(setter(into, strip_option))]
+    pub err_detect: Option<String>,
+    /// Issues kept in [`IntegrityReport::issues`], later ones are only counted
+    #[builder(setter(into, strip_option))]
+    pub max_issues: Option<usize>,
+    /// Longest the decode may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the decode, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for IntegrityOptions {
+    fn default() -> Self {
+        Self {
+            streams: "0".to_string(),
+            err_detect: None,
+            max_issues: Some(10_000),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum IntegrityIssueKind {
+    Decode,
+    MissingPackets,
+    Timestamp,
+    /// Warnings that don't fall into the other kinds
+    Other,
+}
+
+// Checked in order against the lowercased message, first match wins
+const PATTERNS: &[(&str, IntegrityIssueKind)] = &[
+    ("non monoton", IntegrityIssueKind::Timestamp),
+    ("non-monoton", IntegrityIssueKind::Timestamp),
+    ("invalid timestamp", IntegrityIssueKind::Timestamp),
+    ("timestamps are unset", IntegrityIssueKind::Timestamp),
+    ("pts has no value", IntegrityIssueKind::Timestamp),
+    ("invalid dts", IntegrityIssueKind::Timestamp),
+    ("invalid pts", IntegrityIssueKind::Timestamp),
+    ("out of order", IntegrityIssueKind::Timestamp),
+    ("discontinuity", IntegrityIssueKind::Timestamp),
+    (
+        "continuity check failed",
+        IntegrityIssueKind::MissingPackets,
+    ),
+    ("packet corrupt", IntegrityIssueKind::MissingPackets),
+    ("packet mismatch", IntegrityIssueKind::MissingPackets),
+    ("missing picture", IntegrityIssueKind::MissingPackets),
+    ("missing reference", IntegrityIssueKind::MissingPackets),
+    ("incomplete frame", IntegrityIssueKind::MissingPackets),
+    ("truncat", IntegrityIssueKind::MissingPackets),
+    ("error while decoding", IntegrityIssueKind::Decode),
+    ("invalid data found", IntegrityIssueKind::Decode),
+    ("concealing", IntegrityIssueKind::Decode),
+    ("corrupt", IntegrityIssueKind::Decode),
+    ("damaged", IntegrityIssueKind::Decode),
+    ("invalid nal", IntegrityIssueKind::Decode),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IntegrityIssue {
+    pub kind: IntegrityIssueKind,
+    /// `None` for lines printed without a level
+    pub level: Option<LogLevel>,
+    /// The logging context, e.g. `h264` or `mpegts`
+    pub component: Option<String>,
+    pub message: String,
+    /// Approximate position of the issue, the last progress ffmpeg reported before logging it
+    pub timestamp: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    pub issues: Vec<IntegrityIssue>,
+    pub decode_errors: u64,
+    pub missing_packets: u64,
+    pub timestamp_problems: u64,
+    pub other_warnings: u64,
+    /// The last progress position reported, how far the decode got
+    pub decoded_until: Option<Duration>,
+    /// ffmpeg's exit code, non-zero when the decode gave up part way (e.g. with `-xerror`)
+    pub exit_code: Option<i32>,
+}
+
+impl IntegrityReport {
+    /// No decode errors, missing packets or timestamp problems, and ffmpeg decoded through to the end.
+    /// Other warnings are allowed
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.exited_successfully()
+            && self.decode_errors == 0
+            && self.missing_packets == 0
+            && self.timestamp_problems == 0
+    }
+
+    /// Whether ffmpeg exited with code 0, when it didn't [`Self::decoded_until`] is where it gave up
+    #[must_use]
+    pub fn exited_successfully(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    fn record(&mut self, issue: IntegrityIssue, max_issues: Option<usize>) {
+        match issue.kind {
+            IntegrityIssueKind::Decode => self.decode_errors += 1,
+            IntegrityIssueKind::MissingPackets => self.missing_packets += 1,
+            IntegrityIssueKind::Timestamp => self.timestamp_problems += 1,
+            IntegrityIssueKind::Other => self.other_warnings += 1,
+        }
+        if max_issues.is_none_or(|max| self.issues.len() < max) {
+            self.issues.push(issue);
+        }
+    }
+}
+
+/// Decode every mapped stream of `input` to the null muxer, collecting the warnings and errors
+/// ffmpeg logs along the way.
+///
+/// Progress is reported through `progress_tx` exactly like [`crate::ffmpeg::ffmpeg_with_progress`].
+/// A decode that fails part way still returns its report, with [`IntegrityReport::exit_code`] set.
+/// Files ffmpeg can't open at all (it never reported progress) fail with
+/// [`crate::ffmpeg::FfmpegError::ExitedUnsuccessfully`].
+#[instrument(skip(input, progress_tx, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn verify_integrity<P: AsRef<Path>>(
+    input: P,
+    options: &IntegrityOptions,
+    progress_tx: Option<mpsc::Sender<FfmpegProgress>>,
+    cancellation_token: CancellationToken,
+) -> Result<IntegrityReport, AnalysisError> {
+    tracing::debug!("Starting integrity check");
+
+    let (position_tx, mut position_rx) = mpsc::channel::<FfmpegProgress>(100);
+    let (stderr_tx, mut stderr_rx) = mpsc::channel::<String>(100);
+
+    let job_options = job_options(LogLevel::Warning, options.timeout, options.stall_timeout);
+    let run = ffmpeg_monitored(
+        &job_options,
+        Some(position_tx),
+        Some(stderr_tx),
+        None,
+        cancellation_token.clone(),
+        |cmd| {
+            cmd.arg("-nostats");
+            if let Some(err_detect) = &options.err_detect {
+                cmd.arg("-err_detect").arg(err_detect);
+            }
+            cmd.arg("-i").arg(input.as_ref());
+            cmd.arg("-map").arg(&options.streams);
+            cmd.arg("-f").arg("null").arg("-");
+        },
+    );
+
+    let collect = async {
+        let mut report = IntegrityReport::default();
+        loop {
+            tokio::select! {
+                Some(progress) = position_rx.recv() => {
+                    if progress.out_time.is_some() {
+                        report.decoded_until = progress.out_time;
+                    }
+                    // Keep reading positions for the report whether or not anyone's listening
+                    if let Some(progress_tx) = &progress_tx {
+                        send_progress(progress_tx, progress);
+                    }
+                }
+                Some(line) = stderr_rx.recv() => {
+                    let Some(issue) = parse_issue(&line, report.decoded_until) else {
+                        continue;
+                    };
+                    tracing::debug!(kind = %issue.kind, message = %issue.message, "Integrity issue");
+                    report.record(issue, options.max_issues);
+                }
+                else => break,
+            }
+        }
+        report
+    };
+
+    let (result, mut report) = tokio::join!(run, collect);
+    let result = result?;
+
+    // A decode that got going and then failed is what's being checked for, keep its report
+    let failed_code = result.code.filter(|_| !result.success);
+    match failed_code {
+        Some(code) if report.decoded_until.is_some() && result.stop_reason.is_none() => {
+            tracing::warn!(
+                exit_code = code,
+                decoded_until = ?report.decoded_until,
+                "ffmpeg gave up part way through the decode"
+            );
+            report.exit_code = Some(code);
+        }
+        _ => {
+            report.exit_code = result.check_with(&job_options)?.code;
+        }
+    }
+
+    tracing::info!(
+        clean = report.is_clean(),
+        exit_code = ?report.exit_code,
+        decode_errors = report.decode_errors,
+        missing_packets = report.missing_packets,
+        timestamp_problems = report.timestamp_problems,
+        other_warnings = report.other_warnings,
+        "Integrity check complete"
+    );
+
+    Ok(report)
+}
+
+pub(crate) fn parse_issue(line: &str, timestamp: Option<Duration>) -> Option<IntegrityIssue> {
+    let (component, level, message) = split_log_prefixes(line);
+    if message.is_empty() {
+        return None;
+    }
+
+    let lowercase = message.to_ascii_lowercase();
+    let kind = PATTERNS
+        .iter()
+        .find_map(|(pattern, kind)| lowercase.contains(pattern).then_some(*kind))
+        .unwrap_or(match level {
+            // Anything a decoder or demuxer logs as an error is treated as a decode error
+            Some(level) if level <= LogLevel::Error => IntegrityIssueKind::Decode,
+            _ => IntegrityIssueKind::Other,
+        });
+
+    Some(IntegrityIssue {
+        kind,
+        level,
+        component: component.map(ToString::to_string),
+        message: message.to_string(),
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_decoder_errors() {
+        let issue = parse_issue(
+            "[h264 @ 0x55d2a8c1f2c0] [error] error while decoding MB 45 30, bytestream -7",
+            Some(Duration::from_secs(12)),
+        )
+        .unwrap();
+        assert_eq!(
+            issue,
+            IntegrityIssue {
+                kind: IntegrityIssueKind::Decode,
+                level: Some(LogLevel::Error),
+                component: Some("h264".to_string()),
+                message: "error while decoding MB 45 30, bytestream -7".to_string(),
+                timestamp: Some(Duration::from_secs(12)),
+            }
+        );
+    }
+
+    #[test]
+    fn classifies_by_pattern_before_level() {
+        let kind = |line| parse_issue(line, None).map(|issue| issue.kind);
+        assert_eq!(
+            kind("[mpegts @ 0x55d2a8b9e100] [warning] Packet corrupt (stream = 0, dts = 1234560)."),
+            Some(IntegrityIssueKind::MissingPackets)
+        );
+        assert_eq!(
+            kind(
+                "[mpegts @ 0x55d2a8b9e100] [warning] Continuity check failed for pid 256 expected 4 got 6"
+            ),
+            Some(IntegrityIssueKind::MissingPackets)
+        );
+        assert_eq!(
+            kind(
+                "[vist#0:0/h264 @ 0x55d2a8c20a00] [warning] Non-monotonic DTS; previous: 1001, current: 1000; changing to 1002."
+            ),
+            Some(IntegrityIssueKind::Timestamp)
+        );
+        assert_eq!(
+            kind(
+                "[h264 @ 0x55d2a8c1f2c0] [error] concealing 1620 DC, 1620 AC, 1620 MV errors in P frame"
+            ),
+            Some(IntegrityIssueKind::Decode)
+        );
+    }
+
+    #[test]
+    fn unmatched_lines_fall_back_to_their_level() {
+        let kind = |line| parse_issue(line, None).map(|issue| issue.kind);
+        assert_eq!(
+            kind("[aac @ 0x55d2a8c3b000] [error] Reserved bit set."),
+            Some(IntegrityIssueKind::Decode)
+        );
+        assert_eq!(
+            kind(
+                "[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55d2a8b9e100] [warning] stream 1, timescale not set"
+            ),
+            Some(IntegrityIssueKind::Other)
+        );
+        assert_eq!(parse_issue("[h264 @ 0x55d2a8c1f2c0] [error] ", None), None);
+    }
+
+    #[test]
+    fn report_is_only_clean_after_a_successful_exit() {
+        let mut report = IntegrityReport {
+            exit_code: Some(0),
+            ..IntegrityReport::default()
+        };
+        report.record(
+            parse_issue(
+                "[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55d2a8b9e100] [warning] stream 1, timescale not set",
+                None,
+            )
+            .unwrap(),
+            Some(1),
+        );
+        assert!(report.is_clean());
+
+        report.exit_code = Some(69);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn record_counts_issues_past_the_limit() {
+        let mut report = IntegrityReport::default();
+        let issue = parse_issue(
+            "[h264 @ 0x55d2a8c1f2c0] [error] error while decoding MB 45 30, bytestream -7",
+            None,
+        )
+        .unwrap();
+        for _ in 0..3 {
+            report.record(issue.clone(), Some(2));
+        }
+        assert_eq!((report.issues.len(), report.decode_errors), (2, 3));
+    }
+}
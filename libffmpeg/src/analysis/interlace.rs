@@ -0,0 +1,364 @@
+use std::{path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use super::{AnalysisError, job_options};
+use crate::{duration::classify::stderr_tail, ffmpeg::ffmpeg, log::LogLevel};
+
+/// Share of repeated fields above which a mixed progressive/interlaced sample counts as telecined
+const TELECINE_REPEAT_RATIO: f64 = 0.1;
+/// 3:2 pulldown combs 2 of every 5 frames, without repeat detection a mixed sample whose interlaced
+/// share falls in this range counts as telecined
+const TELECINE_INTERLACED_SHARE: std::ops::RangeInclusive<f64> = 0.3..=0.5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct InterlaceOptions {
+    /// Where the sample starts, passed as `-ss` before `-i`
+    pub start: Duration,
+    /// Frames sampled, `None` runs over the whole input
+    #[builder(setter(into, strip_option))]
+    pub frames: Option<u64>,
+    /// Use idet's repeated field counts to tell telecine from mixed content
+    pub detect_repeated_fields: bool,
+    /// Share of determined frames that must be progressive for [`ScanType::Progressive`]
+    pub progressive_ratio: f64,
+    /// Share of determined frames that must be interlaced for [`ScanType::InterlacedTff`]/[`ScanType::InterlacedBff`]
+    pub interlaced_ratio: f64,
+    /// Stream analysed, as passed to `-map`
+    #[builder(setter(into))]
+    pub video_stream: String,
+    /// Longest the sample run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the sample run, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for InterlaceOptions {
+    fn default() -> Self {
+        Self {
+            start: Duration::ZERO,
+            frames: Some(2000),
+            detect_repeated_fields: true,
+            progressive_ratio: 0.95,
+            interlaced_ratio: 0.8,
+            video_stream: "0:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScanType {
+    Progressive,
+    InterlacedTff,
+    InterlacedBff,
+    /// Hard telecined (3:2 pulldown baked into the frames)
+    Telecined,
+    /// Progressive with interlaced sections that don't follow a pulldown pattern
+    Mixed,
+    /// idet couldn't determine any frame
+    Undetermined,
+}
+
+impl ScanType {
+    /// A filter to get progressive output, `None` when there's nothing to do (or nothing known)
+    #[must_use]
+    pub fn suggested_filter(&self) -> Option<&'static str> {
+        match self {
+            Self::InterlacedTff => Some("bwdif=parity=tff"),
+            Self::InterlacedBff => Some("bwdif=parity=bff"),
+            Self::Telecined => Some("fieldmatch,decimate"),
+            Self::Mixed => Some("bwdif=deint=interlaced"),
+            Self::Progressive | Self::Undetermined => None,
+        }
+    }
+}
+
+/// One of idet's `Single frame detection`/`Multi frame detection` summaries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+pub struct FieldOrderCounts {
+    pub tff: u64,
+    pub bff: u64,
+    pub progressive: u64,
+    pub undetermined: u64,
+}
+
+/// idet's `Repeated Fields` summary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+pub struct RepeatedFieldCounts {
+    pub neither: u64,
+    pub top: u64,
+    pub bottom: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct InterlaceReport {
+    /// Classified from the multi frame counts, which idet considers more reliable
+    pub scan_type: ScanType,
+    pub single_frame: FieldOrderCounts,
+    pub multi_frame: FieldOrderCounts,
+    /// `None` unless [`InterlaceOptions::detect_repeated_fields`]
+    pub repeated_fields: Option<RepeatedFieldCounts>,
+}
+
+/// Classify `input` as progressive, interlaced or telecined with `idet`
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn detect_interlace<P: AsRef<Path>>(
+    input: P,
+    options: &InterlaceOptions,
+    cancellation_token: CancellationToken,
+) -> Result<InterlaceReport, AnalysisError> {
+    tracing::debug!("Starting interlace detection");
+
+    // idet logs its summary at info
+    let result = ffmpeg(
+        &job_options(LogLevel::Info, options.timeout, options.stall_timeout),
+        cancellation_token.clone(),
+        |cmd| {
+            cmd.arg("-nostats");
+            if !options.start.is_zero() {
+                cmd.arg("-ss")
+                    .arg(format!("{:.3}", options.start.as_secs_f64()));
+            }
+            cmd.arg("-i").arg(input.as_ref());
+            cmd.arg("-map").arg(&options.video_stream);
+            cmd.arg("-filter:v").arg("idet");
+            if let Some(frames) = options.frames {
+                cmd.arg("-frames:v").arg(frames.to_string());
+            }
+            cmd.arg("-an").arg("-f").arg("null").arg("-");
+        },
+    )
+    .await?;
+
+    let report = parse_idet_summary(&result.stderr_lines, options)?;
+
+    tracing::info!(report = report.as_value(), "Interlace detection complete");
+
+    Ok(report)
+}
+
+/// Parse the summary idet prints when it's uninitialised, and classify it
+pub(crate) fn parse_idet_summary(
+    lines: &[String],
+    options: &InterlaceOptions,
+) -> Result<InterlaceReport, AnalysisError> {
+    let mut single_frame = None;
+    let mut multi_frame = None;
+    let mut repeated_fields = None;
+
+    for line in lines {
+        if let Some((_, rest)) = line.split_once("Single frame detection:") {
+            single_frame = Some(field_order_counts(rest));
+        } else if let Some((_, rest)) = line.split_once("Multi frame detection:") {
+            multi_frame = Some(field_order_counts(rest));
+        } else if let Some((_, rest)) = line.split_once("Repeated Fields:") {
+            let value = |key| count(rest, key);
+            repeated_fields = Some(RepeatedFieldCounts {
+                neither: value("Neither:"),
+                top: value("Top:"),
+                bottom: value("Bottom:"),
+            });
+        }
+    }
+
+    let (Some(single_frame), Some(multi_frame)) = (single_frame, multi_frame) else {
+        return Err(AnalysisError::MissingOutput {
+            expected: "idet summary".to_string(),
+            stderr_tail: stderr_tail(lines),
+        });
+    };
+    let repeated_fields = repeated_fields.filter(|_| options.detect_repeated_fields);
+
+    Ok(InterlaceReport {
+        scan_type: classify(&multi_frame, repeated_fields.as_ref(), options),
+        single_frame,
+        multi_frame,
+        repeated_fields,
+    })
+}
+
+fn field_order_counts(rest: &str) -> FieldOrderCounts {
+    FieldOrderCounts {
+        tff: count(rest, "TFF:"),
+        bff: count(rest, "BFF:"),
+        progressive: count(rest, "Progressive:"),
+        undetermined: count(rest, "Undetermined:"),
+    }
+}
+
+/// The number following `key`, idet pads them with a variable amount of whitespace
+fn count(rest: &str, key: &str) -> u64 {
+    let mut tokens = rest.split_whitespace();
+    tokens
+        .by_ref()
+        .find(|token| *token == key)
+        .and_then(|_| tokens.next())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_default()
+}
+
+fn classify(
+    counts: &FieldOrderCounts,
+    repeated_fields: Option<&RepeatedFieldCounts>,
+    options: &InterlaceOptions,
+) -> ScanType {
+    let interlaced = counts.tff + counts.bff;
+    let determined = interlaced + counts.progressive;
+    if determined == 0 {
+        return ScanType::Undetermined;
+    }
+
+    let progressive_share = counts.progressive as f64 / determined as f64;
+    let interlaced_share = interlaced as f64 / determined as f64;
+
+    if progressive_share >= options.progressive_ratio {
+        return ScanType::Progressive;
+    }
+    if interlaced_share >= options.interlaced_ratio {
+        return if counts.tff >= counts.bff {
+            ScanType::InterlacedTff
+        } else {
+            ScanType::InterlacedBff
+        };
+    }
+
+    let telecined = match repeated_fields {
+        Some(repeated) => {
+            let total = repeated.neither + repeated.top + repeated.bottom;
+            total > 0
+                && (repeated.top + repeated.bottom) as f64 / total as f64 >= TELECINE_REPEAT_RATIO
+        }
+        None => TELECINE_INTERLACED_SHARE.contains(&interlaced_share),
+    };
+
+    if telecined {
+        ScanType::Telecined
+    } else {
+        ScanType::Mixed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(repeated: &str, single: &str, multi: &str) -> Vec<String> {
+        vec![
+            "[Parsed_idet_0 @ 0x55d0c8e4a200] ".to_string() + "Repeated Fields: " + repeated,
+            "[Parsed_idet_0 @ 0x55d0c8e4a200] ".to_string() + "Single frame detection: " + single,
+            "[Parsed_idet_0 @ 0x55d0c8e4a200] ".to_string() + "Multi frame detection: " + multi,
+            "[out#0/null @ 0x55d0c8e3f980] video:860KiB audio:0KiB subtitle:0KiB other streams:0KiB global headers:0KiB muxing overhead: unknown".to_string(),
+        ]
+    }
+
+    #[test]
+    fn parses_progressive_summary() {
+        let lines = summary(
+            "Neither:  1990 Top:     5 Bottom:     5",
+            "TFF:     0 BFF:     0 Progressive:  1870 Undetermined:   130",
+            "TFF:     0 BFF:     0 Progressive:  1998 Undetermined:     2",
+        );
+        let report = parse_idet_summary(&lines, &InterlaceOptions::default()).unwrap();
+        assert_eq!(
+            report,
+            InterlaceReport {
+                scan_type: ScanType::Progressive,
+                single_frame: FieldOrderCounts {
+                    tff: 0,
+                    bff: 0,
+                    progressive: 1870,
+                    undetermined: 130,
+                },
+                multi_frame: FieldOrderCounts {
+                    tff: 0,
+                    bff: 0,
+                    progressive: 1998,
+                    undetermined: 2,
+                },
+                repeated_fields: Some(RepeatedFieldCounts {
+                    neither: 1990,
+                    top: 5,
+                    bottom: 5,
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn classifies_interlaced_field_order() {
+        let lines = summary(
+            "Neither:  2000 Top:     0 Bottom:     0",
+            "TFF:    12 BFF:  1702 Progressive:   140 Undetermined:   146",
+            "TFF:     3 BFF:  1911 Progressive:    52 Undetermined:    34",
+        );
+        let report = parse_idet_summary(&lines, &InterlaceOptions::default()).unwrap();
+        assert_eq!(report.scan_type, ScanType::InterlacedBff);
+    }
+
+    #[test]
+    fn classifies_telecine_from_repeated_fields() {
+        let lines = summary(
+            "Neither:  1600 Top:   200 Bottom:   200",
+            "TFF:   702 BFF:     4 Progressive:  1180 Undetermined:   114",
+            "TFF:   798 BFF:     0 Progressive:  1199 Undetermined:     3",
+        );
+        let report = parse_idet_summary(&lines, &InterlaceOptions::default()).unwrap();
+        assert_eq!(report.scan_type, ScanType::Telecined);
+
+        // Without repeat detection the 40% interlaced share alone points at pulldown
+        let options = InterlaceOptions {
+            detect_repeated_fields: false,
+            ..InterlaceOptions::default()
+        };
+        let report = parse_idet_summary(&lines, &options).unwrap();
+        assert_eq!(
+            (report.scan_type, report.repeated_fields),
+            (ScanType::Telecined, None)
+        );
+    }
+
+    #[test]
+    fn classifies_mixed_and_undetermined() {
+        let mixed = summary(
+            "Neither:  2000 Top:     0 Bottom:     0",
+            "TFF:   180 BFF:     0 Progressive:  1720 Undetermined:   100",
+            "TFF:   210 BFF:     0 Progressive:  1780 Undetermined:    10",
+        );
+        let report = parse_idet_summary(&mixed, &InterlaceOptions::default()).unwrap();
+        assert_eq!(report.scan_type, ScanType::Mixed);
+
+        let undetermined = summary(
+            "Neither:     0 Top:     0 Bottom:     0",
+            "TFF:     0 BFF:     0 Progressive:     0 Undetermined:     0",
+            "TFF:     0 BFF:     0 Progressive:     0 Undetermined:     0",
+        );
+        let report = parse_idet_summary(&undetermined, &InterlaceOptions::default()).unwrap();
+        assert_eq!(report.scan_type, ScanType::Undetermined);
+    }
+
+    #[test]
+    fn missing_summary_is_an_error() {
+        let lines =
+            vec!["[in#0 @ 0x5555] Error opening input: No such file or directory".to_string()];
+        assert!(matches!(
+            parse_idet_summary(&lines, &InterlaceOptions::default()),
+            Err(AnalysisError::MissingOutput { .. })
+        ));
+    }
+}
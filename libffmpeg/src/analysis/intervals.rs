@@ -0,0 +1,377 @@
+use std::{path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use super::{AnalysisError, job_options};
+use crate::{
+    duration::get_duration,
+    ffmpeg::{FfmpegJobOptions, ffmpeg},
+    log::LogLevel,
+};
+
+/// A span of the input a detector matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interval {
+    pub start: Duration,
+    pub end: Duration,
+    pub duration: Duration,
+}
+
+impl Interval {
+    fn new(start: Duration, end: Duration) -> Self {
+        Self {
+            start,
+            end,
+            duration: end.saturating_sub(start),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct BlackDetectOptions {
+    /// `d`, shortest black interval reported
+    pub min_duration: Duration,
+    /// `pic_th`, ratio of black pixels for a picture to count as black
+    pub picture_threshold: f64,
+    /// `pix_th`, luma threshold for a pixel to count as black
+    pub pixel_threshold: f64,
+    /// Stream analysed, as passed to `-map`
+    #[builder(setter(into))]
+    pub video_stream: String,
+    /// Longest the run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the run, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for BlackDetectOptions {
+    fn default() -> Self {
+        Self {
+            min_duration: Duration::from_secs(2),
+            picture_threshold: 0.98,
+            pixel_threshold: 0.10,
+            video_stream: "0:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct FreezeDetectOptions {
+    /// `d`, shortest freeze reported
+    pub min_duration: Duration,
+    /// `n`, noise tolerance as a ratio (0.001 is -60dB)
+    pub noise: f64,
+    /// Stream analysed, as passed to `-map`
+    #[builder(setter(into))]
+    pub video_stream: String,
+    /// Longest the run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the run, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for FreezeDetectOptions {
+    fn default() -> Self {
+        Self {
+            min_duration: Duration::from_secs(2),
+            noise: 0.001,
+            video_stream: "0:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct SilenceDetectOptions {
+    /// `d`, shortest silence reported
+    pub min_duration: Duration,
+    /// `n`, noise tolerance in dB
+    pub noise_db: f64,
+    /// Stream analysed, as passed to `-map`
+    #[builder(setter(into))]
+    pub audio_stream: String,
+    /// Longest the run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the run, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for SilenceDetectOptions {
+    fn default() -> Self {
+        Self {
+            min_duration: Duration::from_secs(2),
+            noise_db: -60.0,
+            audio_stream: "0:a:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+/// Black video intervals, via `blackdetect`
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn detect_black<P: AsRef<Path>>(
+    input: P,
+    options: &BlackDetectOptions,
+    cancellation_token: CancellationToken,
+) -> Result<Vec<Interval>, AnalysisError> {
+    let filter = format!(
+        "blackdetect=d={}:pic_th={}:pix_th={}",
+        options.min_duration.as_secs_f64(),
+        options.picture_threshold,
+        options.pixel_threshold
+    );
+    detect_intervals(
+        input.as_ref(),
+        &options.video_stream,
+        &filter,
+        IntervalKeys::BLACK,
+        false,
+        &job_options(LogLevel::Info, options.timeout, options.stall_timeout),
+        cancellation_token,
+    )
+    .await
+}
+
+/// Frozen video intervals, via `freezedetect`
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn detect_freeze<P: AsRef<Path>>(
+    input: P,
+    options: &FreezeDetectOptions,
+    cancellation_token: CancellationToken,
+) -> Result<Vec<Interval>, AnalysisError> {
+    let filter = format!(
+        "freezedetect=n={}:d={}",
+        options.noise,
+        options.min_duration.as_secs_f64()
+    );
+    detect_intervals(
+        input.as_ref(),
+        &options.video_stream,
+        &filter,
+        IntervalKeys::FREEZE,
+        false,
+        &job_options(LogLevel::Info, options.timeout, options.stall_timeout),
+        cancellation_token,
+    )
+    .await
+}
+
+/// Silent audio intervals, via `silencedetect`
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn detect_silence<P: AsRef<Path>>(
+    input: P,
+    options: &SilenceDetectOptions,
+    cancellation_token: CancellationToken,
+) -> Result<Vec<Interval>, AnalysisError> {
+    let filter = format!(
+        "silencedetect=n={}dB:d={}",
+        options.noise_db,
+        options.min_duration.as_secs_f64()
+    );
+    detect_intervals(
+        input.as_ref(),
+        &options.audio_stream,
+        &filter,
+        IntervalKeys::SILENCE,
+        true,
+        &job_options(LogLevel::Info, options.timeout, options.stall_timeout),
+        cancellation_token,
+    )
+    .await
+}
+
+/// Run `filter` over `stream` with `job_options`, the detectors log at info
+async fn detect_intervals(
+    input: &Path,
+    stream: &str,
+    filter: &str,
+    keys: IntervalKeys,
+    is_audio: bool,
+    job_options: &FfmpegJobOptions,
+    cancellation_token: CancellationToken,
+) -> Result<Vec<Interval>, AnalysisError> {
+    tracing::debug!(filter = %filter, "Starting interval detection");
+
+    let result = ffmpeg(job_options, cancellation_token.clone(), |cmd| {
+        cmd.arg("-nostats");
+        cmd.arg("-i").arg(input);
+        cmd.arg("-map").arg(stream);
+        if is_audio {
+            cmd.arg("-filter:a").arg(filter).arg("-vn");
+        } else {
+            cmd.arg("-filter:v").arg(filter).arg("-an");
+        }
+        cmd.arg("-f").arg("null").arg("-");
+    })
+    .await?;
+
+    let mut parser = IntervalParser::new(keys);
+    for line in &result.stderr_lines {
+        parser.push(line);
+    }
+    let mut intervals = parser.intervals;
+
+    // Some versions don't report an end for an interval still open at EOF, it ends with the input
+    if let Some(start) = parser.open {
+        match get_duration(input, cancellation_token).await {
+            Ok(end) => intervals.push(Interval::new(start, end)),
+            Err(e) => tracing::warn!(
+                error = %e,
+                start = ?start,
+                "Failed to probe duration to close interval open at EOF, dropping it"
+            ),
+        }
+    }
+
+    tracing::info!(intervals = intervals.len(), "Interval detection complete");
+
+    Ok(intervals)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IntervalKeys {
+    start: &'static str,
+    end: &'static str,
+}
+
+impl IntervalKeys {
+    pub(crate) const BLACK: Self = Self {
+        start: "black_start:",
+        end: "black_end:",
+    };
+    pub(crate) const FREEZE: Self = Self {
+        start: "freeze_start:",
+        end: "freeze_end:",
+    };
+    pub(crate) const SILENCE: Self = Self {
+        start: "silence_start:",
+        end: "silence_end:",
+    };
+}
+
+/// Pairs `*_start:`/`*_end:` values from detector log lines, which may share a line (blackdetect)
+/// or not (freezedetect, silencedetect)
+#[derive(Debug)]
+pub(crate) struct IntervalParser {
+    keys: IntervalKeys,
+    open: Option<Duration>,
+    pub(crate) intervals: Vec<Interval>,
+}
+
+impl IntervalParser {
+    pub(crate) fn new(keys: IntervalKeys) -> Self {
+        Self {
+            keys,
+            open: None,
+            intervals: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, line: &str) {
+        if let Some(start) = value_after(line, self.keys.start) {
+            self.open = Some(start);
+        }
+        if let Some(end) = value_after(line, self.keys.end) {
+            let Some(start) = self.open.take() else {
+                tracing::trace!(line = %line, "Interval end without a start");
+                return;
+            };
+            self.intervals.push(Interval::new(start, end));
+        }
+    }
+}
+
+fn value_after(line: &str, key: &str) -> Option<Duration> {
+    let (_, rest) = line.split_once(key)?;
+    let value = rest.split_whitespace().next()?.parse::<f64>().ok()?;
+    // silencedetect can report slightly negative starts
+    Duration::try_from_secs_f64(value.max(0.0)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(keys: IntervalKeys, output: &str) -> IntervalParser {
+        let mut parser = IntervalParser::new(keys);
+        for line in output.lines() {
+            parser.push(line);
+        }
+        parser
+    }
+
+    fn interval(start: f64, end: f64) -> Interval {
+        Interval::new(Duration::from_secs_f64(start), Duration::from_secs_f64(end))
+    }
+
+    #[test]
+    fn parses_blackdetect_single_line_intervals() {
+        let parser = parse(
+            IntervalKeys::BLACK,
+            "\
+[blackdetect @ 0x55c1e0d3a140] black_start:0 black_end:2.04 black_duration:2.04
+[blackdetect @ 0x55c1e0d3a140] black_start:61.52 black_end:63.6 black_duration:2.08",
+        );
+        assert_eq!(
+            parser.intervals,
+            vec![interval(0.0, 2.04), interval(61.52, 63.6)]
+        );
+        assert_eq!(parser.open, None);
+    }
+
+    #[test]
+    fn parses_freezedetect_multi_line_intervals() {
+        let parser = parse(
+            IntervalKeys::FREEZE,
+            "\
+[freezedetect @ 0x7f9e4c004a40] lavfi.freezedetect.freeze_start: 5.005
+[freezedetect @ 0x7f9e4c004a40] lavfi.freezedetect.freeze_duration: 3.003
+[freezedetect @ 0x7f9e4c004a40] lavfi.freezedetect.freeze_end: 8.008",
+        );
+        assert_eq!(parser.intervals, vec![interval(5.005, 8.008)]);
+    }
+
+    #[test]
+    fn clamps_negative_silence_start_and_keeps_open_interval() {
+        let parser = parse(
+            IntervalKeys::SILENCE,
+            "\
+[silencedetect @ 0x600002b40000] silence_start: -0.00133333
+[silencedetect @ 0x600002b40000] silence_end: 1.95721 | silence_duration: 1.95854
+[silencedetect @ 0x600002b40000] silence_start: 118.4",
+        );
+        assert_eq!(parser.intervals, vec![interval(0.0, 1.95721)]);
+        assert_eq!(parser.open, Some(Duration::from_secs_f64(118.4)));
+    }
+
+    #[test]
+    fn end_without_start_is_ignored() {
+        let parser = parse(
+            IntervalKeys::SILENCE,
+            "[silencedetect @ 0x600002b40000] silence_end: 1.95721 | silence_duration: 1.95854",
+        );
+        assert!(parser.intervals.is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use super::{AnalysisError, job_options, parse_f64};
+use crate::{duration::classify::stderr_tail, ffmpeg::ffmpeg, log::LogLevel};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct LoudnessOptions {
+    /// Stream measured, as passed to `-map`
+    #[builder(setter(into))]
+    pub audio_stream: String,
+    /// Collect the per-frame momentary/short-term timeline, this is ~10 lines of ffmpeg output per second of audio
+    pub timeline: bool,
+    /// Longest the measurement may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the measurement, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for LoudnessOptions {
+    fn default() -> Self {
+        Self {
+            audio_stream: "0:a:0".to_string(),
+            timeline: false,
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+/// EBU R128 measurements for a whole input
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoudnessReport {
+    pub integrated_lufs: f64,
+    /// Relative gating threshold used for the integrated loudness
+    pub threshold_lufs: f64,
+    pub loudness_range_lu: f64,
+    pub loudness_range_threshold_lufs: f64,
+    pub loudness_range_low_lufs: f64,
+    pub loudness_range_high_lufs: f64,
+    pub true_peak_dbfs: f64,
+    /// Present when [`LoudnessOptions::timeline`] was set
+    pub timeline: Option<Vec<LoudnessSample>>,
+}
+
+/// A single ebur128 frame log line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoudnessSample {
+    pub time: Duration,
+    pub momentary_lufs: f64,
+    pub short_term_lufs: f64,
+    pub integrated_lufs: f64,
+    pub loudness_range_lu: f64,
+    /// Highest true peak across channels so far
+    pub true_peak_dbfs: Option<f64>,
+}
+
+/// Measure EBU R128 loudness with ffmpeg's `ebur128` filter, decoding to the null muxer
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn measure_loudness<P: AsRef<Path>>(
+    input: P,
+    options: &LoudnessOptions,
+    cancellation_token: CancellationToken,
+) -> Result<LoudnessReport, AnalysisError> {
+    tracing::debug!("Starting loudness measurement");
+
+    let framelog = if options.timeline { "info" } else { "quiet" };
+    // ebur128 logs its summary at info
+    let result = ffmpeg(
+        &job_options(LogLevel::Info, options.timeout, options.stall_timeout),
+        cancellation_token.clone(),
+        |cmd| {
+            cmd.arg("-nostats");
+            cmd.arg("-i").arg(input.as_ref());
+            cmd.arg("-map").arg(&options.audio_stream);
+            cmd.arg("-filter:a")
+                .arg(format!("ebur128=peak=true:framelog={framelog}"));
+            cmd.arg("-f").arg("null").arg("-");
+        },
+    )
+    .await?;
+
+    let mut report = parse_ebur128_summary(&result.stderr_lines)?;
+    if options.timeline {
+        report.timeline = Some(
+            result
+                .stderr_lines
+                .iter()
+                .filter_map(|line| parse_ebur128_frame(line))
+                .collect(),
+        );
+    }
+
+    tracing::info!(
+        integrated_lufs = report.integrated_lufs,
+        loudness_range_lu = report.loudness_range_lu,
+        true_peak_dbfs = report.true_peak_dbfs,
+        "Loudness measured"
+    );
+
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SummarySection {
+    Integrated,
+    Range,
+    TruePeak,
+    Other,
+}
+
+/// Parse the `Summary:` block ebur128 logs when it's torn down
+pub(crate) fn parse_ebur128_summary(lines: &[String]) -> Result<LoudnessReport, AnalysisError> {
+    let missing = |expected: &str| AnalysisError::MissingOutput {
+        expected: expected.to_string(),
+        stderr_tail: stderr_tail(lines),
+    };
+
+    let Some(start) = lines.iter().rposition(|line| line.contains("Summary:")) else {
+        return Err(missing("an ebur128 summary"));
+    };
+
+    let mut section = SummarySection::Other;
+    let mut integrated = None;
+    let mut threshold = None;
+    let mut range = None;
+    let mut range_threshold = None;
+    let mut range_low = None;
+    let mut range_high = None;
+    let mut true_peak = None;
+
+    for line in &lines[start + 1..] {
+        let line = line.trim();
+        match line {
+            "Integrated loudness:" => section = SummarySection::Integrated,
+            "Loudness range:" => section = SummarySection::Range,
+            "True peak:" => section = SummarySection::TruePeak,
+            _ if line.ends_with(':') => section = SummarySection::Other,
+            _ => {}
+        }
+
+        let Some((key, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let Some(value) = rest.split_whitespace().next() else {
+            continue;
+        };
+
+        let slot = match (section, key.trim()) {
+            (SummarySection::Integrated, "I") => &mut integrated,
+            (SummarySection::Integrated, "Threshold") => &mut threshold,
+            (SummarySection::Range, "LRA") => &mut range,
+            (SummarySection::Range, "Threshold") => &mut range_threshold,
+            (SummarySection::Range, "LRA low") => &mut range_low,
+            (SummarySection::Range, "LRA high") => &mut range_high,
+            (SummarySection::TruePeak, "Peak") => &mut true_peak,
+            _ => continue,
+        };
+        *slot = Some(parse_f64(key.trim(), value)?);
+    }
+
+    Ok(LoudnessReport {
+        integrated_lufs: integrated.ok_or_else(|| missing("integrated loudness"))?,
+        threshold_lufs: threshold.ok_or_else(|| missing("integrated loudness threshold"))?,
+        loudness_range_lu: range.ok_or_else(|| missing("loudness range"))?,
+        loudness_range_threshold_lufs: range_threshold
+            .ok_or_else(|| missing("loudness range threshold"))?,
+        loudness_range_low_lufs: range_low.ok_or_else(|| missing("loudness range low"))?,
+        loudness_range_high_lufs: range_high.ok_or_else(|| missing("loudness range high"))?,
+        true_peak_dbfs: true_peak.ok_or_else(|| missing("true peak"))?,
+        timeline: None,
+    })
+}
+
+/// Parse an ebur128 frame log line, e.g.
+/// `[Parsed_ebur128_0 @ 0x...] t: 1.2  TARGET:-23 LUFS  M: -25.3 S:-120.7  I: -25.3 LUFS  LRA: 0.0 LU  FTPK: -4.8 dBFS  TPK: -4.8 dBFS`
+pub(crate) fn parse_ebur128_frame(line: &str) -> Option<LoudnessSample> {
+    if !line.contains("TARGET:") {
+        return None;
+    }
+
+    // Values follow "KEY:" with or without a space, peaks have one value per channel
+    let values = |key: &str| -> Vec<f64> {
+        line.split_once(key)
+            .map(|(_, rest)| {
+                rest.split_whitespace()
+                    .map_while(|token| token.parse::<f64>().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let value = |key: &str| values(key).first().copied();
+
+    let time = value(" t:")?;
+    Some(LoudnessSample {
+        time: Duration::try_from_secs_f64(time).ok()?,
+        momentary_lufs: value(" M:")?,
+        short_term_lufs: value(" S:")?,
+        integrated_lufs: value(" I:")?,
+        loudness_range_lu: value(" LRA:")?,
+        true_peak_dbfs: values(" TPK:").into_iter().reduce(f64::max),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUMMARY: &str = "\
+[Parsed_ebur128_0 @ 0x600003a2c000] t: 29.9     TARGET:-23 LUFS    M: -18.2 S: -19.0     I: -19.6 LUFS       LRA:   5.8 LU  FTPK:  -2.1  -2.4 dBFS  TPK:  -0.4  -0.6 dBFS
+[Parsed_ebur128_0 @ 0x600003a2c000] Summary:
+
+  Integrated loudness:
+    I:         -19.6 LUFS
+    Threshold: -29.7 LUFS
+
+  Loudness range:
+    LRA:         5.8 LU
+    Threshold: -39.7 LUFS
+    LRA low:   -23.6 LUFS
+    LRA high:  -17.8 LUFS
+
+  True peak:
+    Peak:       -0.4 dBFS
+[out#0/null @ 0x600003d28000] video:0KiB audio:5168KiB subtitle:0KiB other streams:0KiB global headers:0KiB muxing overhead: unknown";
+
+    fn lines(output: &str) -> Vec<String> {
+        output.lines().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parses_summary() {
+        let report = parse_ebur128_summary(&lines(SUMMARY)).unwrap();
+        assert_eq!(
+            report,
+            LoudnessReport {
+                integrated_lufs: -19.6,
+                threshold_lufs: -29.7,
+                loudness_range_lu: 5.8,
+                loudness_range_threshold_lufs: -39.7,
+                loudness_range_low_lufs: -23.6,
+                loudness_range_high_lufs: -17.8,
+                true_peak_dbfs: -0.4,
+                timeline: None,
+            }
+        );
+    }
+
+    #[test]
+    fn missing_summary_is_an_error() {
+        let stderr = lines("[in#0 @ 0x5555] Error opening input: No such file or directory");
+        assert!(matches!(
+            parse_ebur128_summary(&stderr),
+            Err(AnalysisError::MissingOutput { .. })
+        ));
+    }
+
+    #[test]
+    fn incomplete_summary_is_an_error() {
+        let truncated = SUMMARY
+            .lines()
+            .take_while(|line| !line.contains("True peak"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(matches!(
+            parse_ebur128_summary(&lines(&truncated)),
+            Err(AnalysisError::MissingOutput { expected, .. }) if expected == "true peak"
+        ));
+    }
+
+    #[test]
+    fn parses_frame_with_per_channel_peaks() {
+        let sample = parse_ebur128_frame(SUMMARY.lines().next().unwrap()).unwrap();
+        assert_eq!(
+            sample,
+            LoudnessSample {
+                time: Duration::from_secs_f64(29.9),
+                momentary_lufs: -18.2,
+                short_term_lufs: -19.0,
+                integrated_lufs: -19.6,
+                loudness_range_lu: 5.8,
+                true_peak_dbfs: Some(-0.4),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_frame_before_short_term_window_fills() {
+        let line = "[Parsed_ebur128_0 @ 0x600003a2c000] t: 0.399979   TARGET:-23 LUFS    M:-120.7 S:-120.7     I: -70.0 LUFS       LRA:   0.0 LU";
+        let sample = parse_ebur128_frame(line).unwrap();
+        assert_eq!(
+            (
+                sample.momentary_lufs,
+                sample.short_term_lufs,
+                sample.true_peak_dbfs
+            ),
+            (-120.7, -120.7, None)
+        );
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert_eq!(parse_ebur128_frame("  Integrated loudness:"), None);
+        assert_eq!(
+            parse_ebur128_frame("Stream #0:0: Audio: pcm_s16le, 48000 Hz, stereo, s16, 1536 kb/s"),
+            None
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use derive_builder::Builder;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio::sync::mpsc;
+use tokio_util::{future::FutureExt, sync::CancellationToken};
+use tracing::{Instrument, instrument};
+use valuable::Valuable;
+
+use super::{AnalysisError, escape_filter_value, job_options};
+use crate::{
+    ffmpeg::{FfmpegProgress, ffmpeg_monitored},
+    log::LogLevel,
+};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Video,
+    Audio,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct MetadataOptions {
+    /// Filters run before the print sink, e.g. `signalstats` or `astats=metadata=1:reset=1`
+    #[builder(setter(into))]
+    pub filter: String,
+    /// Whether `stream` is video (`metadata`) or audio (`ametadata`)
+    pub kind: MediaKind,
+    /// Only print this key, frames without it are skipped
+    #[builder(setter(into, strip_option))]
+    pub key: Option<String>,
+    /// Stream analysed, as passed to `-map`
+    #[builder(setter(into))]
+    pub stream: String,
+    /// Longest the run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the run, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for MetadataOptions {
+    fn default() -> Self {
+        Self {
+            filter: String::new(),
+            kind: MediaKind::Video,
+            key: None,
+            stream: "0:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+/// The metadata a filter attached to one frame
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameMetadata {
+    /// Index of the frame as seen by the print sink
+    pub frame: u64,
+    pub pts: Option<i64>,
+    /// `None` for frames without a (non negative) timestamp
+    pub pts_time: Option<Duration>,
+    pub values: BTreeMap<String, String>,
+}
+
+impl FrameMetadata {
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// `key`'s value as a number, `None` if missing or not numeric
+    #[must_use]
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.get(key)?.trim().parse().ok()
+    }
+}
+
+/// Run `options.filter` followed by a `metadata=print`/`ametadata=print` sink over `input`, yielding
+/// each frame's metadata as ffmpeg prints it. The sink writes to stdout, values longer than the
+/// sink's 128 byte line buffer are truncated.
+///
+/// A frame is yielded once the next one starts (or ffmpeg exits), since the sink doesn't mark the
+/// end of a frame's values. The stream ends after the last frame, or with a single error if ffmpeg
+/// fails. Dropping it stops ffmpeg. Progress is reported through `progress_tx` exactly like
+/// [`crate::ffmpeg::ffmpeg_with_progress`].
+#[instrument(skip(input, progress_tx, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub fn frame_metadata<P: AsRef<Path>>(
+    input: P,
+    options: &MetadataOptions,
+    progress_tx: Option<mpsc::Sender<FfmpegProgress>>,
+    cancellation_token: CancellationToken,
+) -> impl Stream<Item = Result<FrameMetadata, AnalysisError>> + Send + 'static {
+    let input = input.as_ref().to_path_buf();
+    let options = options.clone();
+    frame_stream(
+        cancellation_token,
+        move |frames_tx, cancellation_token| async move {
+            run_frame_metadata(&input, &options, frames_tx, progress_tx, cancellation_token).await
+        },
+    )
+}
+
+/// Run `run` in the background, yielding the frames it sends. Dropping the stream cancels the token
+/// `run` is given and stops forwarding, so `run`'s sends fail rather than waiting on a full channel
+fn frame_stream<Run, Fut>(
+    cancellation_token: CancellationToken,
+    run: Run,
+) -> impl Stream<Item = Result<FrameMetadata, AnalysisError>> + Send + 'static
+where
+    Run: FnOnce(mpsc::Sender<FrameMetadata>, CancellationToken) -> Fut,
+    Fut: Future<Output = Result<(), AnalysisError>> + Send + 'static,
+{
+    let cancellation_token = cancellation_token.child_token();
+    let guard = cancellation_token.clone().drop_guard();
+    let (tx, rx) = mpsc::channel(100);
+    let (frames_tx, mut frames_rx) = mpsc::channel(100);
+    let run = run(frames_tx, cancellation_token);
+
+    tokio::spawn(
+        async move {
+            // Owns `frames_rx`, so it's dropped as soon as the stream is
+            let forward = async move {
+                while let Some(frame) = frames_rx.recv().await {
+                    if tx.send(Ok(frame)).await.is_err() {
+                        tracing::debug!("Frame metadata stream dropped");
+                        return None;
+                    }
+                }
+                Some(tx)
+            };
+
+            let (result, tx) = tokio::join!(run, forward);
+            if let (Err(e), Some(tx)) = (result, tx) {
+                let _ = tx.send(Err(e)).await;
+            }
+        }
+        .in_current_span(),
+    );
+
+    futures::stream::unfold((rx, guard), |(mut rx, guard)| async move {
+        let item = rx.recv().await?;
+        Some((item, (rx, guard)))
+    })
+}
+
+/// Drive the print sink, sending each frame to `frames_tx` as it completes
+pub(crate) async fn run_frame_metadata(
+    input: &Path,
+    options: &MetadataOptions,
+    frames_tx: mpsc::Sender<FrameMetadata>,
+    progress_tx: Option<mpsc::Sender<FfmpegProgress>>,
+    cancellation_token: CancellationToken,
+) -> Result<(), AnalysisError> {
+    let filter = metadata_filter(options);
+    tracing::debug!(filter = %filter, "Starting frame metadata run");
+
+    let (stdout_tx, mut stdout_rx) = mpsc::channel::<String>(100);
+
+    let job_options = job_options(LogLevel::Warning, options.timeout, options.stall_timeout);
+    let run = ffmpeg_monitored(
+        &job_options,
+        progress_tx,
+        None,
+        Some(stdout_tx),
+        cancellation_token.clone(),
+        |cmd| {
+            cmd.arg("-nostats");
+            cmd.arg("-i").arg(input);
+            cmd.arg("-map").arg(&options.stream);
+            match options.kind {
+                MediaKind::Video => cmd.arg("-filter:v").arg(&filter).arg("-an"),
+                MediaKind::Audio => cmd.arg("-filter:a").arg(&filter).arg("-vn"),
+            };
+            cmd.arg("-f").arg("null").arg("-");
+        },
+    );
+
+    let collect = async {
+        let mut parser = FrameMetadataParser::default();
+        let mut frames = 0_u64;
+        loop {
+            let frame = match stdout_rx.recv().await {
+                Some(line) => match parser.push(&line) {
+                    Some(frame) => frame,
+                    None => continue,
+                },
+                None => match parser.finish() {
+                    Some(frame) => frame,
+                    None => break,
+                },
+            };
+            frames += 1;
+            // Keep draining stdout whatever happens to the frames, ffmpeg blocks on a full pipe
+            match frames_tx
+                .send(frame)
+                .with_cancellation_token(&cancellation_token)
+                .await
+            {
+                Some(Ok(())) => {}
+                Some(Err(e)) => tracing::trace!(error = %e, "Frame metadata receiver dropped"),
+                None => tracing::trace!("Cancelled, dropping frame metadata"),
+            }
+        }
+        frames
+    };
+
+    let (result, frames) = tokio::join!(run, collect);
+    result?.check_with(&job_options)?;
+
+    tracing::info!(frames, "Frame metadata run complete");
+
+    Ok(())
+}
+
+/// `options.filter` followed by the print sink, writing to stdout rather than the log so values
+/// can't be confused with (or interleaved with) other log lines. `direct=1` writes each line as it's
+/// printed, the sink formats lines into a 128 byte buffer so longer values are truncated
+pub(crate) fn metadata_filter(options: &MetadataOptions) -> String {
+    let sink = match options.kind {
+        MediaKind::Video => "metadata",
+        MediaKind::Audio => "ametadata",
+    };
+    let mut filter = if options.filter.is_empty() {
+        format!("{sink}=print:file='pipe\\:1':direct=1")
+    } else {
+        format!("{},{sink}=print:file='pipe\\:1':direct=1", options.filter)
+    };
+    if let Some(key) = &options.key {
+        filter.push_str(":key=");
+        filter.push_str(&escape_filter_value(key));
+    }
+    filter
+}
+
+/// Groups the print sink's `frame:N pts:N pts_time:N` header with the `key=value` lines after it
+#[derive(Debug, Default)]
+pub(crate) struct FrameMetadataParser {
+    current: Option<FrameMetadata>,
+}
+
+impl FrameMetadataParser {
+    /// Returns the previous frame once the next one starts
+    pub(crate) fn push(&mut self, line: &str) -> Option<FrameMetadata> {
+        if line.starts_with("frame:") {
+            let field = |key: &str| {
+                line.split_whitespace()
+                    .find_map(|token| token.strip_prefix(key))
+            };
+            let header = FrameMetadata {
+                frame: field("frame:")
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_default(),
+                pts: field("pts:").and_then(|value| value.parse().ok()),
+                pts_time: field("pts_time:")
+                    .and_then(|value| value.parse::<f64>().ok())
+                    .and_then(|value| Duration::try_from_secs_f64(value).ok()),
+                values: BTreeMap::new(),
+            };
+            return self.current.replace(header);
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            tracing::trace!(line = %line, "Unrecognised metadata line");
+            return None;
+        };
+        let Some(current) = &mut self.current else {
+            tracing::trace!(line = %line, "Metadata value without a frame header");
+            return None;
+        };
+        current.values.insert(key.to_string(), value.to_string());
+        None
+    }
+
+    /// The last frame, once the input is exhausted
+    pub(crate) fn finish(&mut self) -> Option<FrameMetadata> {
+        self.current.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // signalstats,metadata=print:key=lavfi.signalstats.YAVG:file='pipe\:1' over a 25fps input
+    const OUTPUT: &str = "\
+frame:0    pts:0       pts_time:0
+lavfi.signalstats.YAVG=16.023
+frame:1    pts:1       pts_time:0.04
+lavfi.signalstats.YAVG=112.5
+frame:2    pts:2       pts_time:0.08
+lavfi.signalstats.YAVG=113.102";
+
+    fn parse(output: &str) -> Vec<FrameMetadata> {
+        let mut parser = FrameMetadataParser::default();
+        let mut frames = output
+            .lines()
+            .filter_map(|line| parser.push(line))
+            .collect::<Vec<_>>();
+        frames.extend(parser.finish());
+        frames
+    }
+
+    #[test]
+    fn groups_values_under_their_frame() {
+        let frames = parse(OUTPUT);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            frames[1],
+            FrameMetadata {
+                frame: 1,
+                pts: Some(1),
+                pts_time: Some(Duration::from_secs_f64(0.04)),
+                values: BTreeMap::from([(
+                    "lavfi.signalstats.YAVG".to_string(),
+                    "112.5".to_string()
+                )]),
+            }
+        );
+        assert_eq!(
+            frames
+                .iter()
+                .map(|frame| frame.get_f64("lavfi.signalstats.YAVG"))
+                .collect::<Vec<_>>(),
+            vec![Some(16.023), Some(112.5), Some(113.102)]
+        );
+    }
+
+    #[test]
+    fn frame_without_timestamp() {
+        let frames =
+            parse("frame:0    pts:NOPTS   pts_time:NOPTS\nlavfi.astats.Overall.RMS_level=-inf");
+        assert_eq!(
+            (
+                frames[0].pts,
+                frames[0].pts_time,
+                frames[0].get_f64("lavfi.astats.Overall.RMS_level")
+            ),
+            (None, None, Some(f64::NEG_INFINITY))
+        );
+    }
+
+    #[test]
+    fn values_before_a_header_are_dropped() {
+        assert!(parse("lavfi.signalstats.YAVG=16.023").is_empty());
+    }
+
+    #[test]
+    fn filter_escapes_key() {
+        let options = MetadataOptions {
+            filter: "signalstats".to_string(),
+            key: Some("lavfi.odd:key,with'quote".to_string()),
+            ..MetadataOptions::default()
+        };
+        assert_eq!(
+            metadata_filter(&options),
+            r"signalstats,metadata=print:file='pipe\:1':direct=1:key=lavfi.odd\\:key\,with\\\'quote"
+        );
+    }
+
+    #[tokio::test]
+    async fn dropping_the_stream_lets_the_run_finish() {
+        use futures::StreamExt;
+
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let stream = frame_stream(CancellationToken::new(), |frames_tx, _| async move {
+            // More frames than both channels hold, sent without looking at the token
+            for frame in 0..500 {
+                let _ = frames_tx
+                    .send(FrameMetadata {
+                        frame,
+                        pts: None,
+                        pts_time: None,
+                        values: BTreeMap::new(),
+                    })
+                    .await;
+            }
+            let _ = done_tx.send(());
+            Ok(())
+        });
+
+        let mut stream = Box::pin(stream);
+        assert_eq!(stream.next().await.unwrap().unwrap().frame, 0);
+        drop(stream);
+
+        tokio::time::timeout(Duration::from_secs(5), done_rx)
+            .await
+            .expect("run finished")
+            .unwrap();
+    }
+}
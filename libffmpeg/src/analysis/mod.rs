@@ -0,0 +1,132 @@
+pub mod crop;
+pub mod integrity;
+pub mod interlace;
+pub mod intervals;
+pub mod loudness;
+pub mod metadata;
+pub mod normalize;
+pub mod phash;
+pub mod quality;
+pub mod scene;
+
+use liberror::AnyError;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use valuable::Valuable;
+
+use crate::{
+    duration::DurationError,
+    ffmpeg::{FfmpegError, FfmpegJobOptions},
+    log::LogLevel,
+};
+
+pub use crop::{CropDetectOptions, CropDetectOptionsBuilder, CropDetection, CropRect, detect_crop};
+pub use integrity::{
+    IntegrityIssue, IntegrityIssueKind, IntegrityOptions, IntegrityOptionsBuilder, IntegrityReport,
+    verify_integrity,
+};
+pub use interlace::{
+    FieldOrderCounts, InterlaceOptions, InterlaceOptionsBuilder, InterlaceReport,
+    RepeatedFieldCounts, ScanType, detect_interlace,
+};
+pub use intervals::{
+    BlackDetectOptions, BlackDetectOptionsBuilder, FreezeDetectOptions, FreezeDetectOptionsBuilder,
+    Interval, SilenceDetectOptions, SilenceDetectOptionsBuilder, detect_black, detect_freeze,
+    detect_silence,
+};
+pub use loudness::{
+    LoudnessOptions, LoudnessOptionsBuilder, LoudnessReport, LoudnessSample, measure_loudness,
+};
+pub use metadata::{
+    FrameMetadata, MediaKind, MetadataOptions, MetadataOptionsBuilder, frame_metadata,
+};
+pub use normalize::{
+    LoudnessTarget, LoudnormMeasurement, LoudnormPass, NormalizationType, NormalizeOptions,
+    NormalizeOptionsBuilder, NormalizeReport, measure_loudnorm, normalize_audio,
+};
+pub use phash::{
+    FrameHash, HashAlgorithm, VideoHash, VideoHashOptions, VideoHashOptionsBuilder,
+    hamming_distance, hash_video,
+};
+pub use quality::{
+    PsnrFrame, PsnrSummary, QualityFrame, QualityMetric, QualityOptions, QualityOptionsBuilder,
+    QualityReport, SsimFrame, SsimSummary, VmafSummary, compare_quality,
+};
+pub use scene::{SceneCut, SceneOptions, SceneOptionsBuilder, detect_scenes};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Valuable, Error)]
+pub enum AnalysisError {
+    #[error(transparent)]
+    Ffmpeg {
+        #[from]
+        inner_error: FfmpegError,
+    },
+    #[error(transparent)]
+    Duration {
+        #[from]
+        inner_error: DurationError,
+    },
+    #[error("Expected ffmpeg to report {expected}: {}", stderr_tail.join("\n"))]
+    MissingOutput {
+        expected: String,
+        stderr_tail: Vec<String>,
+    },
+    #[error("Failed to parse {what} from '{value}': {inner_error}")]
+    Parse {
+        what: String,
+        value: String,
+        inner_error: AnyError,
+    },
+    #[error("Failed to read '{path}': {inner_error}")]
+    ReadFile { path: String, inner_error: AnyError },
+    #[error("Failed to communicate with ffmpeg: {inner_error}")]
+    Pipe { inner_error: AnyError },
+}
+
+/// Options for the ffmpeg runs behind the analyses, logging at `log_level` (most filters report at
+/// info) and limited by the analysis' `timeout` and `stall_timeout`. Every stderr line is kept
+/// since that's where the filters report
+pub(crate) fn job_options(
+    log_level: LogLevel,
+    timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+) -> FfmpegJobOptions {
+    FfmpegJobOptions {
+        log_level,
+        timeout,
+        stall_timeout,
+        ..FfmpegJobOptions::default()
+    }
+}
+
+/// Escape `value` for use as a filter option inside a filtergraph, both levels of ffmpeg's
+/// filtergraph escaping apply
+pub(crate) fn escape_filter_value(value: &str) -> String {
+    let escape = |value: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+    escape(
+        &escape(value, &['\\', ':', '\'']),
+        &['\\', '\'', '[', ']', ',', ';'],
+    )
+}
+
+pub(crate) fn parse_f64(what: &str, value: &str) -> Result<f64, AnalysisError> {
+    value
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| AnalysisError::Parse {
+            what: what.to_string(),
+            value: value.to_string(),
+            inner_error: e.into(),
+        })
+}
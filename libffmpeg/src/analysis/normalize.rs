@@ -0,0 +1,375 @@
+use std::{path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use super::{AnalysisError, LoudnessReport, job_options, parse_f64};
+use crate::{duration::classify::stderr_tail, ffmpeg::ffmpeg, log::LogLevel};
+
+/// Loudness targets passed to `loudnorm` as `I`, `TP` and `LRA`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct LoudnessTarget {
+    pub integrated_lufs: f64,
+    pub true_peak_dbfs: f64,
+    pub loudness_range_lu: f64,
+}
+
+impl Default for LoudnessTarget {
+    /// loudnorm's own defaults
+    fn default() -> Self {
+        Self {
+            integrated_lufs: -24.0,
+            true_peak_dbfs: -2.0,
+            loudness_range_lu: 7.0,
+        }
+    }
+}
+
+impl LoudnessTarget {
+    /// Whether `report` is within `tolerance_lu` of the integrated target and under the true peak ceiling
+    #[must_use]
+    pub fn is_met_by(&self, report: &LoudnessReport, tolerance_lu: f64) -> bool {
+        (report.integrated_lufs - self.integrated_lufs).abs() <= tolerance_lu
+            && report.true_peak_dbfs <= self.true_peak_dbfs
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct NormalizeOptions {
+    pub target: LoudnessTarget,
+    /// Ask for linear (constant gain) normalisation, loudnorm falls back to dynamic when the
+    /// measurements don't allow it. See [`NormalizeReport::normalization_type`]
+    pub linear: bool,
+    /// Stream normalised, as passed to `-map`
+    #[builder(setter(into))]
+    pub audio_stream: String,
+    /// `-ar` for the output, loudnorm resamples to 192kHz internally and outputs that otherwise
+    #[builder(setter(into, strip_option))]
+    pub sample_rate: Option<u32>,
+    /// Longest each pass may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during each pass, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        Self {
+            target: LoudnessTarget::default(),
+            linear: true,
+            audio_stream: "0:a:0".to_string(),
+            sample_rate: Some(48_000),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationType {
+    Linear,
+    Dynamic,
+}
+
+/// One side of the measurements `loudnorm` prints with `print_format=json`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct LoudnormMeasurement {
+    pub integrated_lufs: f64,
+    pub true_peak_dbfs: f64,
+    pub loudness_range_lu: f64,
+    pub threshold_lufs: f64,
+}
+
+/// Everything `loudnorm` reports for a single pass
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct LoudnormPass {
+    pub input: LoudnormMeasurement,
+    pub output: LoudnormMeasurement,
+    pub normalization_type: NormalizationType,
+    pub target_offset_lu: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct NormalizeReport {
+    /// The input, as measured by the first pass
+    pub before: LoudnormMeasurement,
+    /// The output, as measured by the second pass
+    pub after: LoudnormMeasurement,
+    /// What the second pass actually did
+    pub normalization_type: NormalizationType,
+    pub first_pass: LoudnormPass,
+    pub second_pass: LoudnormPass,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoudnormJson {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    output_i: String,
+    output_tp: String,
+    output_lra: String,
+    output_thresh: String,
+    normalization_type: String,
+    target_offset: String,
+}
+
+/// Run loudnorm's measurement pass over `input` to the null muxer
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn measure_loudnorm<P: AsRef<Path>>(
+    input: P,
+    options: &NormalizeOptions,
+    cancellation_token: CancellationToken,
+) -> Result<LoudnormPass, AnalysisError> {
+    tracing::debug!("Starting loudnorm measurement pass");
+
+    let target = options.target;
+    // loudnorm prints its measurements at info
+    let result = ffmpeg(
+        &job_options(LogLevel::Info, options.timeout, options.stall_timeout),
+        cancellation_token.clone(),
+        |cmd| {
+            cmd.arg("-nostats");
+            cmd.arg("-i").arg(input.as_ref());
+            cmd.arg("-map").arg(&options.audio_stream);
+            cmd.arg("-filter:a").arg(format!(
+                "loudnorm=I={:.2}:TP={:.2}:LRA={:.2}:print_format=json",
+                target.integrated_lufs, target.true_peak_dbfs, target.loudness_range_lu
+            ));
+            cmd.arg("-f").arg("null").arg("-");
+        },
+    )
+    .await?;
+
+    parse_loudnorm_json(&result.stderr_lines).inspect(|pass| {
+        tracing::info!(pass = pass.as_value(), "loudnorm measurement pass complete");
+    })
+}
+
+/// Two-pass `loudnorm` normalisation of `input` into `output`.
+///
+/// `prepare_output` is called after the filter options and before `output` is added, use it for
+/// codecs, extra `-map`s (e.g. `-map 0:v? -c:v copy` to keep video) or `-y`.
+#[instrument(skip(input, output, cancellation_token, prepare_output), fields(input_path = %input.as_ref().display(), output_path = %output.as_ref().display()))]
+pub async fn normalize_audio<I, O, Prepare>(
+    input: I,
+    output: O,
+    options: &NormalizeOptions,
+    cancellation_token: CancellationToken,
+    prepare_output: Prepare,
+) -> Result<NormalizeReport, AnalysisError>
+where
+    I: AsRef<Path>,
+    O: AsRef<Path>,
+    Prepare: FnOnce(&mut Command),
+{
+    let first_pass = measure_loudnorm(&input, options, cancellation_token.clone()).await?;
+
+    let filter = second_pass_filter(options, &first_pass.input, first_pass.target_offset_lu);
+    tracing::debug!(filter = %filter, "Starting loudnorm normalisation pass");
+
+    // loudnorm prints its measurements at info
+    let result = ffmpeg(
+        &job_options(LogLevel::Info, options.timeout, options.stall_timeout),
+        cancellation_token.clone(),
+        |cmd| {
+            cmd.arg("-nostats");
+            cmd.arg("-i").arg(input.as_ref());
+            cmd.arg("-map").arg(&options.audio_stream);
+            cmd.arg("-filter:a").arg(&filter);
+            if let Some(sample_rate) = options.sample_rate {
+                cmd.arg("-ar").arg(sample_rate.to_string());
+            }
+            prepare_output(cmd);
+            cmd.arg(output.as_ref());
+        },
+    )
+    .await?;
+
+    let second_pass = parse_loudnorm_json(&result.stderr_lines)?;
+
+    let report = NormalizeReport {
+        before: first_pass.input,
+        after: second_pass.output,
+        normalization_type: second_pass.normalization_type,
+        first_pass,
+        second_pass,
+    };
+
+    tracing::info!(report = report.as_value(), "Audio normalised");
+
+    Ok(report)
+}
+
+fn second_pass_filter(
+    options: &NormalizeOptions,
+    measured: &LoudnormMeasurement,
+    target_offset_lu: f64,
+) -> String {
+    // loudnorm rejects values outside these ranges, silent input measures as -inf
+    let target = options.target;
+    format!(
+        "loudnorm=I={:.2}:TP={:.2}:LRA={:.2}:measured_I={:.2}:measured_TP={:.2}:measured_LRA={:.2}:measured_thresh={:.2}:offset={:.2}:linear={}:print_format=json",
+        target.integrated_lufs,
+        target.true_peak_dbfs,
+        target.loudness_range_lu,
+        measured.integrated_lufs.clamp(-99.0, 0.0),
+        measured.true_peak_dbfs.clamp(-99.0, 99.0),
+        measured.loudness_range_lu.clamp(0.0, 99.0),
+        measured.threshold_lufs.clamp(-99.0, 0.0),
+        target_offset_lu.clamp(-99.0, 99.0),
+        options.linear,
+    )
+}
+
+/// Parse the last JSON block loudnorm printed to stderr
+pub(crate) fn parse_loudnorm_json(lines: &[String]) -> Result<LoudnormPass, AnalysisError> {
+    let missing = || AnalysisError::MissingOutput {
+        expected: "loudnorm JSON output".to_string(),
+        stderr_tail: stderr_tail(lines),
+    };
+
+    let end = lines
+        .iter()
+        .rposition(|line| line.trim() == "}")
+        .ok_or_else(missing)?;
+    let start = lines[..end]
+        .iter()
+        .rposition(|line| line.trim() == "{")
+        .ok_or_else(missing)?;
+    let block = lines[start..=end].join("\n");
+
+    let json = serde_json::from_str::<LoudnormJson>(&block).map_err(|e| AnalysisError::Parse {
+        what: "loudnorm JSON".to_string(),
+        value: block.clone(),
+        inner_error: e.into(),
+    })?;
+
+    Ok(LoudnormPass {
+        input: LoudnormMeasurement {
+            integrated_lufs: parse_f64("input_i", &json.input_i)?,
+            true_peak_dbfs: parse_f64("input_tp", &json.input_tp)?,
+            loudness_range_lu: parse_f64("input_lra", &json.input_lra)?,
+            threshold_lufs: parse_f64("input_thresh", &json.input_thresh)?,
+        },
+        output: LoudnormMeasurement {
+            integrated_lufs: parse_f64("output_i", &json.output_i)?,
+            true_peak_dbfs: parse_f64("output_tp", &json.output_tp)?,
+            loudness_range_lu: parse_f64("output_lra", &json.output_lra)?,
+            threshold_lufs: parse_f64("output_thresh", &json.output_thresh)?,
+        },
+        normalization_type: json
+            .normalization_type
+            .trim()
+            .to_ascii_lowercase()
+            .parse()
+            .map_err(|e: strum::ParseError| AnalysisError::Parse {
+                what: "normalization_type".to_string(),
+                value: json.normalization_type.clone(),
+                inner_error: e.into(),
+            })?,
+        target_offset_lu: parse_f64("target_offset", &json.target_offset)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST_PASS: &str = "\
+[Parsed_loudnorm_0 @ 0x7f8b5c004a40] 
+{
+\t\"input_i\" : \"-27.61\",
+\t\"input_tp\" : \"-4.47\",
+\t\"input_lra\" : \"18.06\",
+\t\"input_thresh\" : \"-39.20\",
+\t\"output_i\" : \"-24.58\",
+\t\"output_tp\" : \"-2.00\",
+\t\"output_lra\" : \"9.90\",
+\t\"output_thresh\" : \"-35.92\",
+\t\"normalization_type\" : \"dynamic\",
+\t\"target_offset\" : \"0.58\"
+}
+[out#0/null @ 0x600002a2c000] video:0KiB audio:5168KiB subtitle:0KiB other streams:0KiB global headers:0KiB muxing overhead: unknown
+size=N/A time=00:00:29.98 bitrate=N/A speed= 412x";
+
+    fn lines(output: &str) -> Vec<String> {
+        output.lines().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parses_json_block() {
+        let pass = parse_loudnorm_json(&lines(FIRST_PASS)).unwrap();
+        assert_eq!(
+            pass,
+            LoudnormPass {
+                input: LoudnormMeasurement {
+                    integrated_lufs: -27.61,
+                    true_peak_dbfs: -4.47,
+                    loudness_range_lu: 18.06,
+                    threshold_lufs: -39.20,
+                },
+                output: LoudnormMeasurement {
+                    integrated_lufs: -24.58,
+                    true_peak_dbfs: -2.00,
+                    loudness_range_lu: 9.90,
+                    threshold_lufs: -35.92,
+                },
+                normalization_type: NormalizationType::Dynamic,
+                target_offset_lu: 0.58,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_silent_input() {
+        let silent = FIRST_PASS
+            .replace("\"-27.61\"", "\"-inf\"")
+            .replace("\"-4.47\"", "\"-inf\"")
+            .replace("\"dynamic\"", "\"Linear\"");
+        let pass = parse_loudnorm_json(&lines(&silent)).unwrap();
+        assert!(pass.input.integrated_lufs.is_infinite());
+        assert!(pass.input.true_peak_dbfs.is_infinite());
+        assert_eq!(pass.normalization_type, NormalizationType::Linear);
+    }
+
+    #[test]
+    fn missing_json_is_an_error() {
+        let stderr =
+            lines("[aist#0:0/pcm_s16le @ 0x5555] Error while decoding stream: Invalid data");
+        assert!(matches!(
+            parse_loudnorm_json(&stderr),
+            Err(AnalysisError::MissingOutput { .. })
+        ));
+    }
+
+    #[test]
+    fn second_pass_clamps_measurements() {
+        let measured = LoudnormMeasurement {
+            integrated_lufs: f64::NEG_INFINITY,
+            true_peak_dbfs: f64::NEG_INFINITY,
+            loudness_range_lu: 0.0,
+            threshold_lufs: f64::NEG_INFINITY,
+        };
+        let filter = second_pass_filter(&NormalizeOptions::default(), &measured, 0.58);
+        assert_eq!(
+            filter,
+            "loudnorm=I=-24.00:TP=-2.00:LRA=7.00:measured_I=-99.00:measured_TP=-99.00:measured_LRA=0.00:measured_thresh=-99.00:offset=0.58:linear=true:print_format=json"
+        );
+    }
+}
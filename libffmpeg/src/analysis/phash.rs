@@ -0,0 +1,330 @@
+use std::{path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use super::{AnalysisError, job_options};
+use crate::{
+    duration::get_duration,
+    ffmpeg::{FfmpegJob, job::JobStdout},
+    log::LogLevel,
+};
+
+/// Side of the square pHash frames are scaled to before the DCT
+const PHASH_SIZE: usize = 32;
+/// Side of the low frequency block of DCT coefficients kept, 8x8 gives a 64 bit hash
+const PHASH_BLOCK: usize = 8;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// Difference hash, compares neighbouring pixels of a 9x8 thumbnail. Cheap, tolerant of
+    /// re-encodes and scaling
+    DHash,
+    /// DCT based hash of a 32x32 thumbnail, more tolerant of colour and contrast changes
+    PHash,
+}
+
+impl HashAlgorithm {
+    fn frame_size(self) -> (usize, usize) {
+        match self {
+            Self::DHash => (9, 8),
+            Self::PHash => (PHASH_SIZE, PHASH_SIZE),
+        }
+    }
+
+    fn hash(self, pixels: &[u8]) -> u64 {
+        match self {
+            Self::DHash => dhash(pixels),
+            Self::PHash => phash(pixels),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct VideoHashOptions {
+    /// Number of evenly spaced frames hashed
+    pub frames: usize,
+    pub algorithm: HashAlgorithm,
+    /// Stream hashed, as passed to `-map`
+    #[builder(setter(into))]
+    pub video_stream: String,
+    /// Longest the hashing run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress while hashing, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for VideoHashOptions {
+    fn default() -> Self {
+        Self {
+            frames: 32,
+            algorithm: HashAlgorithm::PHash,
+            video_stream: "0:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameHash {
+    /// Approximate position of the hashed frame
+    pub timestamp: Duration,
+    pub hash: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoHash {
+    pub algorithm: HashAlgorithm,
+    pub frames: Vec<FrameHash>,
+}
+
+impl VideoHash {
+    /// Share (0.0-1.0) of the shorter sequence's frames with a match within `max_distance` bits in
+    /// the other, ignoring order so trimmed or re-cut copies still match. Hashes made with different
+    /// algorithms are never similar
+    #[must_use]
+    pub fn similarity(&self, other: &Self, max_distance: u32) -> f64 {
+        if self.algorithm != other.algorithm {
+            return 0.0;
+        }
+        let (shorter, longer) = if self.frames.len() <= other.frames.len() {
+            (&self.frames, &other.frames)
+        } else {
+            (&other.frames, &self.frames)
+        };
+        if shorter.is_empty() {
+            return 0.0;
+        }
+
+        let matched = shorter
+            .iter()
+            .filter(|frame| {
+                longer
+                    .iter()
+                    .any(|candidate| hamming_distance(frame.hash, candidate.hash) <= max_distance)
+            })
+            .count();
+        matched as f64 / shorter.len() as f64
+    }
+}
+
+/// Number of differing bits between two hashes
+#[must_use]
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Perceptual hashes of `options.frames` evenly spaced frames of `input`.
+///
+/// ffmpeg decodes, picks and downscales the frames, writing them to stdout as `gray` rawvideo.
+#[instrument(skip(input, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn hash_video<P: AsRef<Path>>(
+    input: P,
+    options: &VideoHashOptions,
+    cancellation_token: CancellationToken,
+) -> Result<VideoHash, AnalysisError> {
+    let input = input.as_ref();
+    let duration = get_duration(input, cancellation_token.clone()).await?;
+    let (width, height) = options.algorithm.frame_size();
+    let frame_bytes = width * height;
+    let interval = duration.div_f64(options.frames.max(1) as f64);
+
+    let (frames_tx, mut frames_rx) = mpsc::channel::<Vec<u8>>(100);
+    let job_options = job_options(LogLevel::Error, options.timeout, options.stall_timeout);
+    let job = FfmpegJob::spawn_with(
+        &job_options,
+        JobStdout::Chunks {
+            size: frame_bytes,
+            tx: frames_tx,
+        },
+        None,
+        cancellation_token,
+        |cmd| {
+            cmd.arg("-nostats");
+            cmd.arg("-i").arg(input);
+            cmd.arg("-map").arg(&options.video_stream);
+            cmd.arg("-filter:v").arg(format!(
+                "fps=1/{:.6},scale={width}:{height}:flags=area,format=gray",
+                interval.as_secs_f64().max(0.001)
+            ));
+            cmd.arg("-frames:v").arg(options.frames.to_string());
+            cmd.arg("-an").arg("-f").arg("rawvideo").arg("pipe:1");
+        },
+    )
+    .await?;
+
+    tracing::info!(
+        algorithm = %options.algorithm,
+        frames = options.frames,
+        "Hashing frames"
+    );
+
+    let hash_frames = async {
+        let mut frames = Vec::with_capacity(options.frames);
+        while let Some(frame) = frames_rx.recv().await {
+            frames.push(FrameHash {
+                timestamp: interval * frames.len() as u32,
+                hash: options.algorithm.hash(&frame),
+            });
+        }
+        frames
+    };
+
+    let (result, frames) = tokio::join!(job.wait(), hash_frames);
+    result?;
+
+    tracing::info!(frames = frames.len(), "Frame hashing complete");
+
+    Ok(VideoHash {
+        algorithm: options.algorithm,
+        frames,
+    })
+}
+
+/// dHash of a 9x8 gray frame, one bit per horizontally adjacent pair
+pub(crate) fn dhash(pixels: &[u8]) -> u64 {
+    let mut hash = 0u64;
+    for row in pixels.chunks_exact(9) {
+        for pair in row.windows(2) {
+            hash = (hash << 1) | u64::from(pair[0] < pair[1]);
+        }
+    }
+    hash
+}
+
+/// pHash of a 32x32 gray frame, one bit per low frequency DCT coefficient above their median
+pub(crate) fn phash(pixels: &[u8]) -> u64 {
+    let cosines: Vec<[f64; PHASH_SIZE]> = (0..PHASH_BLOCK)
+        .map(|u| {
+            std::array::from_fn(|x| {
+                (std::f64::consts::PI * u as f64 * (2.0 * x as f64 + 1.0)
+                    / (2.0 * PHASH_SIZE as f64))
+                    .cos()
+            })
+        })
+        .collect();
+
+    // Separable DCT-II, only the low frequency block is needed
+    let mut rows = [[0.0; PHASH_BLOCK]; PHASH_SIZE];
+    for (y, row) in pixels.chunks_exact(PHASH_SIZE).enumerate() {
+        for u in 0..PHASH_BLOCK {
+            rows[y][u] = row
+                .iter()
+                .zip(&cosines[u])
+                .map(|(pixel, cosine)| f64::from(*pixel) * cosine)
+                .sum();
+        }
+    }
+    let mut coefficients = [0.0; PHASH_BLOCK * PHASH_BLOCK];
+    for v in 0..PHASH_BLOCK {
+        for u in 0..PHASH_BLOCK {
+            coefficients[v * PHASH_BLOCK + u] =
+                (0..PHASH_SIZE).map(|y| rows[y][u] * cosines[v][y]).sum();
+        }
+    }
+
+    // The DC term is the average brightness, leave it out of the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+
+    coefficients.iter().fold(0u64, |hash, coefficient| {
+        (hash << 1) | u64::from(*coefficient > median)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A deterministic noisy 32x32 frame, like one of ffmpeg's `gray` rawvideo frames
+    fn noise_frame(seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..PHASH_SIZE * PHASH_SIZE)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8 % 200
+            })
+            .collect()
+    }
+
+    fn frame_hashes(hashes: &[u64]) -> Vec<FrameHash> {
+        hashes
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| FrameHash {
+                timestamp: Duration::from_secs(index as u64),
+                hash: *hash,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dhash_follows_horizontal_gradients() {
+        let rising = (0..8).flat_map(|_| 0..9u8).collect::<Vec<_>>();
+        let falling = rising.iter().map(|pixel| 8 - pixel).collect::<Vec<_>>();
+        assert_eq!(dhash(&rising), u64::MAX);
+        assert_eq!(dhash(&falling), 0);
+        assert_eq!(dhash(&[128; 72]), 0);
+    }
+
+    #[test]
+    fn phash_ignores_brightness() {
+        let frame = noise_frame(7);
+        let brighter = frame.iter().map(|pixel| pixel + 40).collect::<Vec<_>>();
+        assert!(hamming_distance(phash(&frame), phash(&brighter)) <= 1);
+    }
+
+    #[test]
+    fn phash_tells_different_frames_apart() {
+        let frame = noise_frame(7);
+        let mirrored = frame
+            .chunks_exact(PHASH_SIZE)
+            .flat_map(|row| row.iter().rev().copied())
+            .collect::<Vec<_>>();
+        assert!(hamming_distance(phash(&frame), phash(&noise_frame(8))) > 16);
+        assert!(hamming_distance(phash(&frame), phash(&mirrored)) > 16);
+    }
+
+    #[test]
+    fn similarity_ignores_order_and_algorithm_mismatches() {
+        let original = VideoHash {
+            algorithm: HashAlgorithm::PHash,
+            frames: frame_hashes(&[0x00ff, 0xff00, 0xf0f0, 0x0f0f]),
+        };
+        // A re-cut copy, two frames one bit off and one frame that isn't in the original
+        let recut = VideoHash {
+            algorithm: HashAlgorithm::PHash,
+            frames: frame_hashes(&[0xf0f1, 0x00fe, u64::MAX]),
+        };
+        assert_eq!(hamming_distance(0xf0f0, 0xf0f1), 1);
+        assert_eq!(
+            (
+                recut.similarity(&original, 1),
+                original.similarity(&recut, 0)
+            ),
+            (2.0 / 3.0, 0.0)
+        );
+
+        let dhash = VideoHash {
+            algorithm: HashAlgorithm::DHash,
+            ..original.clone()
+        };
+        assert_eq!(original.similarity(&dhash, 64).to_bits(), 0.0_f64.to_bits());
+    }
+}
@@ -0,0 +1,642 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use super::{AnalysisError, escape_filter_value, job_options};
+use crate::{
+    duration::classify::stderr_tail,
+    ffmpeg::{ffmpeg, ffmpeg_raw},
+    log::LogLevel,
+};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum QualityMetric {
+    Psnr,
+    Ssim,
+    /// Skipped when the ffmpeg build doesn't list `libvmaf` in `-filters`
+    Vmaf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct QualityOptions {
+    pub metrics: Vec<QualityMetric>,
+    /// Passed to libvmaf as `model=`, e.g. `version=vmaf_4k_v0.6.1`
+    #[builder(setter(into, strip_option))]
+    pub vmaf_model: Option<String>,
+    /// Scale the distorted stream to the reference's resolution, with `scale=rw:rh` and the reference
+    /// as its size input. Off by default, the two input `scale` needs ffmpeg 7.1 or newer and fails
+    /// to parse on older versions. Without it both streams must already have the same resolution
+    pub scale_to_reference: bool,
+    /// Resample both streams to this frame rate with `fps`, for encodes that changed it
+    #[builder(setter(into, strip_option))]
+    pub frame_rate: Option<String>,
+    /// Convert both streams to this pixel format, the metrics need matching formats
+    #[builder(setter(into, strip_option))]
+    pub pixel_format: Option<String>,
+    /// Stream compared from the reference (input 0), as a filtergraph input label
+    #[builder(setter(into))]
+    pub reference_stream: String,
+    /// Stream compared from the distorted file (input 1), as a filtergraph input label
+    #[builder(setter(into))]
+    pub distorted_stream: String,
+    /// Longest the comparison may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the comparison, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for QualityOptions {
+    fn default() -> Self {
+        Self {
+            metrics: vec![
+                QualityMetric::Psnr,
+                QualityMetric::Ssim,
+                QualityMetric::Vmaf,
+            ],
+            vmaf_model: None,
+            scale_to_reference: false,
+            frame_rate: None,
+            pixel_format: None,
+            reference_stream: "0:v:0".to_string(),
+            distorted_stream: "1:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+/// A frame's `psnr` stats, `u`/`v` are missing for gray input
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct PsnrFrame {
+    pub mse_avg: f64,
+    pub psnr_avg: f64,
+    pub psnr_y: f64,
+    pub psnr_u: Option<f64>,
+    pub psnr_v: Option<f64>,
+}
+
+/// A frame's `ssim` stats, `u`/`v` are missing for gray input
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct SsimFrame {
+    pub y: f64,
+    pub u: Option<f64>,
+    pub v: Option<f64>,
+    pub all: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct QualityFrame {
+    /// Zero based index of the compared frame
+    pub frame: u64,
+    pub psnr: Option<PsnrFrame>,
+    pub ssim: Option<SsimFrame>,
+    pub vmaf: Option<f64>,
+}
+
+/// The summary `psnr` logs, in dB
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct PsnrSummary {
+    pub y: f64,
+    pub u: Option<f64>,
+    pub v: Option<f64>,
+    pub average: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// The summary `ssim` logs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct SsimSummary {
+    pub y: f64,
+    pub u: Option<f64>,
+    pub v: Option<f64>,
+    pub all: f64,
+}
+
+/// libvmaf's pooled scores
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct VmafSummary {
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub harmonic_mean: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityReport {
+    pub frames: Vec<QualityFrame>,
+    pub psnr: Option<PsnrSummary>,
+    pub ssim: Option<SsimSummary>,
+    /// `None` when libvmaf wasn't requested or isn't available
+    pub vmaf: Option<VmafSummary>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VmafLog {
+    frames: Vec<VmafLogFrame>,
+    pooled_metrics: BTreeMap<String, VmafLogPooled>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VmafLogFrame {
+    #[serde(rename = "frameNum")]
+    frame_num: u64,
+    metrics: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VmafLogPooled {
+    min: f64,
+    max: f64,
+    mean: f64,
+    harmonic_mean: f64,
+}
+
+/// Compare `distorted` against `reference` with `psnr`, `ssim` and (when available) `libvmaf`.
+///
+/// Both streams are rebased to start at zero, and optionally scaled, resampled and converted so
+/// the metrics compare like with like. Comparison stops at the end of the shorter stream.
+#[instrument(skip(reference, distorted, cancellation_token), fields(reference_path = %reference.as_ref().display(), distorted_path = %distorted.as_ref().display()))]
+pub async fn compare_quality<R, D>(
+    reference: R,
+    distorted: D,
+    options: &QualityOptions,
+    cancellation_token: CancellationToken,
+) -> Result<QualityReport, AnalysisError>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let vmaf_log = if options.metrics.contains(&QualityMetric::Vmaf) {
+        if filter_available("libvmaf", cancellation_token.clone()).await? {
+            Some(vmaf_log_path())
+        } else {
+            tracing::warn!("libvmaf requested but not available in this ffmpeg build, skipping");
+            None
+        }
+    } else {
+        None
+    };
+
+    let graph = quality_filtergraph(options, vmaf_log.as_deref());
+    tracing::debug!(filter = %graph, "Starting quality comparison");
+
+    // the metrics log their summaries at info
+    let job_options = job_options(LogLevel::Info, options.timeout, options.stall_timeout);
+    let result = ffmpeg_raw(&job_options, cancellation_token.clone(), |cmd| {
+        cmd.arg("-nostats");
+        cmd.arg("-i").arg(reference.as_ref());
+        cmd.arg("-i").arg(distorted.as_ref());
+        cmd.arg("-filter_complex").arg(&graph);
+        cmd.arg("-an").arg("-f").arg("null").arg("-");
+    })
+    .await;
+
+    // Read (and clean up) the log before checking the run, it's expected to be missing if it failed
+    let vmaf_log = match &vmaf_log {
+        Some(path) => Some(read_vmaf_log(path).await),
+        None => None,
+    };
+
+    let result = result?.check_with(&job_options)?;
+
+    let mut frames = BTreeMap::<u64, QualityFrame>::new();
+    for line in &result.stdout_lines {
+        let Some(n) = number(line, "n:").map(|n| n as u64) else {
+            continue;
+        };
+        // psnr and ssim number frames from 1
+        let index = n.saturating_sub(1);
+        let frame = frames.entry(index).or_insert_with(|| empty_frame(index));
+        if let Some(psnr) = parse_psnr_frame(line) {
+            frame.psnr = Some(psnr);
+        } else if let Some(ssim) = parse_ssim_frame(line) {
+            frame.ssim = Some(ssim);
+        }
+    }
+
+    let psnr = if options.metrics.contains(&QualityMetric::Psnr) {
+        Some(parse_psnr_summary(&result.stderr_lines)?)
+    } else {
+        None
+    };
+    let ssim = if options.metrics.contains(&QualityMetric::Ssim) {
+        Some(parse_ssim_summary(&result.stderr_lines)?)
+    } else {
+        None
+    };
+
+    let vmaf = match vmaf_log.transpose()? {
+        Some(log) => {
+            for log_frame in &log.frames {
+                frames
+                    .entry(log_frame.frame_num)
+                    .or_insert_with(|| empty_frame(log_frame.frame_num))
+                    .vmaf = log_frame.metrics.get("vmaf").copied();
+            }
+            let pooled =
+                log.pooled_metrics
+                    .get("vmaf")
+                    .ok_or_else(|| AnalysisError::MissingOutput {
+                        expected: "pooled vmaf score in the libvmaf log".to_string(),
+                        stderr_tail: stderr_tail(&result.stderr_lines),
+                    })?;
+            Some(VmafSummary {
+                mean: pooled.mean,
+                min: pooled.min,
+                max: pooled.max,
+                harmonic_mean: pooled.harmonic_mean,
+            })
+        }
+        None => None,
+    };
+
+    let report = QualityReport {
+        frames: frames.into_values().collect(),
+        psnr,
+        ssim,
+        vmaf,
+    };
+
+    tracing::info!(
+        frames = report.frames.len(),
+        psnr = ?report.psnr,
+        ssim = ?report.ssim,
+        vmaf = ?report.vmaf,
+        "Quality comparison complete"
+    );
+
+    Ok(report)
+}
+
+fn empty_frame(frame: u64) -> QualityFrame {
+    QualityFrame {
+        frame,
+        psnr: None,
+        ssim: None,
+        vmaf: None,
+    }
+}
+
+/// Whether `ffmpeg -filters` lists `name`
+async fn filter_available(
+    name: &str,
+    cancellation_token: CancellationToken,
+) -> Result<bool, AnalysisError> {
+    let result = ffmpeg(
+        &job_options(LogLevel::Error, None, None),
+        cancellation_token,
+        |cmd| {
+            cmd.arg("-filters");
+        },
+    )
+    .await?;
+
+    Ok(result
+        .stdout_lines
+        .iter()
+        .any(|line| line.split_whitespace().nth(1) == Some(name)))
+}
+
+fn vmaf_log_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "libffmpeg-vmaf-{}.json",
+        uuid::Uuid::new_v4().simple()
+    ))
+}
+
+/// Build the comparison graph, every metric gets the distorted stream as its first input
+pub(crate) fn quality_filtergraph(options: &QualityOptions, vmaf_log: Option<&Path>) -> String {
+    let mut prepare = String::from("settb=AVTB,setpts=PTS-STARTPTS");
+    if let Some(frame_rate) = &options.frame_rate {
+        let _ = write!(prepare, ",fps={frame_rate}");
+    }
+    if let Some(pixel_format) = &options.pixel_format {
+        let _ = write!(prepare, ",format={pixel_format}");
+    }
+
+    let mut graph = format!(
+        "[{}]{prepare}[dist];[{}]{prepare}[ref]",
+        options.distorted_stream, options.reference_stream
+    );
+    let (dist, reference) = if options.scale_to_reference {
+        // scale2ref is deprecated, scale takes the reference frames as a second input instead but
+        // consumes them, so the metrics get a copy
+        graph.push_str(
+            ";[ref]split=2[ref_scaled][ref_size];[dist][ref_size]scale=w=rw:h=rh:flags=bicubic[dist_scaled]",
+        );
+        ("dist_scaled", "ref_scaled")
+    } else {
+        ("dist", "ref")
+    };
+
+    let metrics = options
+        .metrics
+        .iter()
+        .filter_map(|metric| match metric {
+            QualityMetric::Psnr => Some("psnr=stats_file=-:shortest=1".to_string()),
+            QualityMetric::Ssim => Some("ssim=stats_file=-:shortest=1".to_string()),
+            QualityMetric::Vmaf => vmaf_log.map(|log_path| {
+                let mut vmaf = format!(
+                    "libvmaf=log_fmt=json:log_path={}:shortest=1",
+                    escape_filter_value(&log_path.display().to_string())
+                );
+                if let Some(model) = &options.vmaf_model {
+                    let _ = write!(vmaf, ":model={model}");
+                }
+                vmaf
+            }),
+        })
+        .collect::<Vec<_>>();
+
+    match metrics.len() {
+        0 => {
+            let _ = write!(graph, ";[{dist}]nullsink;[{reference}]nullsink");
+        }
+        1 => {
+            let _ = write!(graph, ";[{dist}][{reference}]{}", metrics[0]);
+        }
+        count => {
+            let labels = |prefix: &str| {
+                (0..count).fold(String::new(), |mut labels, index| {
+                    let _ = write!(labels, "[{prefix}{index}]");
+                    labels
+                })
+            };
+            let _ = write!(
+                graph,
+                ";[{dist}]split={count}{};[{reference}]split={count}{}",
+                labels("dist"),
+                labels("ref")
+            );
+            for (index, metric) in metrics.iter().enumerate() {
+                let _ = write!(graph, ";[dist{index}][ref{index}]{metric}");
+            }
+        }
+    }
+
+    graph
+}
+
+/// The number after `key` in whitespace separated `key:value` tokens
+fn number(line: &str, key: &str) -> Option<f64> {
+    line.split_whitespace()
+        .find_map(|token| token.strip_prefix(key))
+        .and_then(|value| value.parse().ok())
+}
+
+pub(crate) fn parse_psnr_frame(line: &str) -> Option<PsnrFrame> {
+    Some(PsnrFrame {
+        mse_avg: number(line, "mse_avg:")?,
+        psnr_avg: number(line, "psnr_avg:")?,
+        psnr_y: number(line, "psnr_y:")?,
+        psnr_u: number(line, "psnr_u:"),
+        psnr_v: number(line, "psnr_v:"),
+    })
+}
+
+pub(crate) fn parse_ssim_frame(line: &str) -> Option<SsimFrame> {
+    Some(SsimFrame {
+        y: number(line, "Y:")?,
+        u: number(line, "U:"),
+        v: number(line, "V:"),
+        all: number(line, "All:")?,
+    })
+}
+
+pub(crate) fn parse_psnr_summary(lines: &[String]) -> Result<PsnrSummary, AnalysisError> {
+    lines
+        .iter()
+        .rev()
+        .filter_map(|line| line.split_once("] PSNR ").map(|(_, rest)| rest))
+        .find_map(|rest| {
+            Some(PsnrSummary {
+                y: number(rest, "y:")?,
+                u: number(rest, "u:"),
+                v: number(rest, "v:"),
+                average: number(rest, "average:")?,
+                min: number(rest, "min:")?,
+                max: number(rest, "max:")?,
+            })
+        })
+        .ok_or_else(|| AnalysisError::MissingOutput {
+            expected: "psnr summary".to_string(),
+            stderr_tail: stderr_tail(lines),
+        })
+}
+
+pub(crate) fn parse_ssim_summary(lines: &[String]) -> Result<SsimSummary, AnalysisError> {
+    lines
+        .iter()
+        .rev()
+        .filter_map(|line| line.split_once("] SSIM ").map(|(_, rest)| rest))
+        .find_map(|rest| {
+            Some(SsimSummary {
+                y: number(rest, "Y:")?,
+                u: number(rest, "U:"),
+                v: number(rest, "V:"),
+                all: number(rest, "All:")?,
+            })
+        })
+        .ok_or_else(|| AnalysisError::MissingOutput {
+            expected: "ssim summary".to_string(),
+            stderr_tail: stderr_tail(lines),
+        })
+}
+
+async fn read_vmaf_log(path: &Path) -> Result<VmafLog, AnalysisError> {
+    let log = tokio::fs::read_to_string(path).await;
+    let _ = tokio::fs::remove_file(path).await.inspect_err(|e| {
+        tracing::warn!(error = %e, path = %path.display(), "Failed to remove libvmaf log");
+    });
+    let log = log.map_err(|e| AnalysisError::ReadFile {
+        path: path.display().to_string(),
+        inner_error: e.into(),
+    })?;
+    parse_vmaf_log(&log)
+}
+
+fn parse_vmaf_log(log: &str) -> Result<VmafLog, AnalysisError> {
+    serde_json::from_str(log).map_err(|e| AnalysisError::Parse {
+        what: "libvmaf JSON log".to_string(),
+        value: log.chars().take(200).collect(),
+        inner_error: e.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSNR_FRAME: &str = "n:1 mse_avg:2.17 mse_y:2.87 mse_u:0.86 mse_v:0.74 psnr_avg:44.77 psnr_y:43.55 psnr_u:48.79 psnr_v:49.44";
+    const SSIM_FRAME: &str = "n:1 Y:0.987654 U:0.991234 V:0.990123 All:0.988765 (19.168212)";
+
+    fn lines(output: &str) -> Vec<String> {
+        output.lines().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parses_psnr_frame() {
+        assert_eq!(
+            parse_psnr_frame(PSNR_FRAME),
+            Some(PsnrFrame {
+                mse_avg: 2.17,
+                psnr_avg: 44.77,
+                psnr_y: 43.55,
+                psnr_u: Some(48.79),
+                psnr_v: Some(49.44),
+            })
+        );
+        // gray input has no chroma planes
+        assert_eq!(
+            parse_psnr_frame("n:7 mse_avg:0.52 mse_y:0.52 psnr_avg:50.97 psnr_y:50.97")
+                .map(|frame| (frame.psnr_u, frame.psnr_v)),
+            Some((None, None))
+        );
+        assert_eq!(parse_psnr_frame(SSIM_FRAME), None);
+    }
+
+    #[test]
+    fn parses_ssim_frame() {
+        assert_eq!(
+            parse_ssim_frame(SSIM_FRAME),
+            Some(SsimFrame {
+                y: 0.987_654,
+                u: Some(0.991_234),
+                v: Some(0.990_123),
+                all: 0.988_765,
+            })
+        );
+        assert_eq!(parse_ssim_frame(PSNR_FRAME), None);
+    }
+
+    #[test]
+    fn parses_summaries() {
+        let stderr = lines(
+            "\
+[Parsed_psnr_6 @ 0x600001f3c0b0] PSNR y:43.551234 u:48.790123 v:49.441234 average:44.771234 min:38.123456 max:51.234567
+[Parsed_ssim_7 @ 0x600001f3c2c0] SSIM Y:0.987654 (19.082158) U:0.991234 (20.519012) V:0.990123 (20.045645) All:0.988765 (19.494532)
+[out#0/null @ 0x600001e38000] video:2100KiB audio:0KiB subtitle:0KiB other streams:0KiB global headers:0KiB muxing overhead: unknown",
+        );
+        assert_eq!(
+            parse_psnr_summary(&stderr).unwrap(),
+            PsnrSummary {
+                y: 43.551_234,
+                u: Some(48.790_123),
+                v: Some(49.441_234),
+                average: 44.771_234,
+                min: 38.123_456,
+                max: 51.234_567,
+            }
+        );
+        assert_eq!(
+            parse_ssim_summary(&stderr).unwrap(),
+            SsimSummary {
+                y: 0.987_654,
+                u: Some(0.991_234),
+                v: Some(0.990_123),
+                all: 0.988_765,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_summaries_with_levels() {
+        let stderr = lines(
+            "\
+[Parsed_psnr_6 @ 0x600001f3c0b0] [info] PSNR y:43.551234 u:48.790123 v:49.441234 average:44.771234 min:38.123456 max:51.234567
+[Parsed_ssim_7 @ 0x600001f3c2c0] [info] SSIM Y:0.987654 (19.082158) U:0.991234 (20.519012) V:0.990123 (20.045645) All:0.988765 (19.494532)",
+        );
+        let unlevelled = stderr
+            .iter()
+            .map(|line| line.replace(" [info]", ""))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parse_psnr_summary(&stderr).unwrap(),
+            parse_psnr_summary(&unlevelled).unwrap()
+        );
+        assert_eq!(
+            parse_ssim_summary(&stderr).unwrap(),
+            parse_ssim_summary(&unlevelled).unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_summary_is_an_error() {
+        let stderr = lines("[in#1 @ 0x5555] Error opening input: No such file or directory");
+        assert!(matches!(
+            parse_psnr_summary(&stderr),
+            Err(AnalysisError::MissingOutput { .. })
+        ));
+        assert!(matches!(
+            parse_ssim_summary(&stderr),
+            Err(AnalysisError::MissingOutput { .. })
+        ));
+    }
+
+    #[test]
+    fn filtergraph_scales_with_the_reference_as_size_input() {
+        let options = QualityOptions {
+            metrics: vec![QualityMetric::Psnr],
+            scale_to_reference: true,
+            ..QualityOptions::default()
+        };
+        assert_eq!(
+            quality_filtergraph(&options, None),
+            "[1:v:0]settb=AVTB,setpts=PTS-STARTPTS[dist];[0:v:0]settb=AVTB,setpts=PTS-STARTPTS[ref];\
+[ref]split=2[ref_scaled][ref_size];[dist][ref_size]scale=w=rw:h=rh:flags=bicubic[dist_scaled];\
+[dist_scaled][ref_scaled]psnr=stats_file=-:shortest=1"
+        );
+    }
+
+    #[test]
+    fn filtergraph_splits_for_multiple_metrics() {
+        let options = QualityOptions {
+            metrics: vec![QualityMetric::Psnr, QualityMetric::Ssim],
+            ..QualityOptions::default()
+        };
+        assert!(quality_filtergraph(&options, None).ends_with(
+            ";[dist]split=2[dist0][dist1];[ref]split=2[ref0][ref1];\
+[dist0][ref0]psnr=stats_file=-:shortest=1;[dist1][ref1]ssim=stats_file=-:shortest=1"
+        ));
+    }
+
+    #[test]
+    fn default_filtergraph_works_before_ffmpeg_7_1() {
+        let graph = quality_filtergraph(&QualityOptions::default(), None);
+        assert!(!graph.contains("scale"));
+        assert!(graph.contains("[dist0][ref0]psnr="));
+    }
+
+    #[test]
+    fn vmaf_log_paths_are_unique() {
+        assert_ne!(vmaf_log_path(), vmaf_log_path());
+    }
+
+    #[test]
+    fn escapes_vmaf_log_path() {
+        assert_eq!(
+            escape_filter_value("C:\\tmp\\vmaf [1].json"),
+            "C\\\\:\\\\\\\\tmp\\\\\\\\vmaf \\[1\\].json"
+        );
+    }
+}
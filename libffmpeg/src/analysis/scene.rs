@@ -0,0 +1,177 @@
+use std::{path::Path, time::Duration};
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use super::{
+    AnalysisError,
+    metadata::{FrameMetadata, MediaKind, MetadataOptions, run_frame_metadata},
+};
+use crate::ffmpeg::FfmpegProgress;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct SceneOptions {
+    /// Minimum `scene` score (0.0-1.0) for a frame to count as a cut
+    pub threshold: f64,
+    /// Cuts closer than this to the previous accepted cut are dropped
+    pub min_gap: Duration,
+    /// Stream analysed, as passed to `-map`
+    #[builder(setter(into))]
+    pub video_stream: String,
+    /// Longest the run may take before ffmpeg is shut down, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::timeout`]
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest ffmpeg may go without progress during the run, see
+    /// [`crate::ffmpeg::FfmpegJobOptions::stall_timeout`]
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for SceneOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.3,
+            min_gap: Duration::ZERO,
+            video_stream: "0:v:0".to_string(),
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SceneCut {
+    pub timestamp: Duration,
+    pub score: f64,
+}
+
+/// Detect scene changes with `select='gt(scene,X)',metadata=print`, read through
+/// [`super::frame_metadata`]'s sink.
+///
+/// Cuts are sent to `cuts_tx` as ffmpeg finds them (once the next cut starts, or ffmpeg exits) and
+/// returned once it finishes, progress is reported through `progress_tx` exactly like
+/// [`crate::ffmpeg::ffmpeg_with_progress`].
+#[instrument(skip(input, cuts_tx, progress_tx, cancellation_token), fields(input_path = %input.as_ref().display()))]
+pub async fn detect_scenes<P: AsRef<Path>>(
+    input: P,
+    options: &SceneOptions,
+    cuts_tx: Option<mpsc::Sender<SceneCut>>,
+    progress_tx: Option<mpsc::Sender<FfmpegProgress>>,
+    cancellation_token: CancellationToken,
+) -> Result<Vec<SceneCut>, AnalysisError> {
+    tracing::debug!("Starting scene detection");
+
+    let metadata_options = MetadataOptions {
+        filter: format!("select='gt(scene,{})'", options.threshold),
+        kind: MediaKind::Video,
+        key: Some(SCENE_SCORE_KEY.to_string()),
+        stream: options.video_stream.clone(),
+        timeout: options.timeout,
+        stall_timeout: options.stall_timeout,
+    };
+    let (frames_tx, mut frames_rx) = mpsc::channel::<FrameMetadata>(100);
+
+    let run = run_frame_metadata(
+        input.as_ref(),
+        &metadata_options,
+        frames_tx,
+        progress_tx,
+        cancellation_token,
+    );
+
+    let collect = async {
+        let mut cuts = Vec::new();
+        while let Some(frame) = frames_rx.recv().await {
+            let Some(cut) = scene_cut(&frame) else {
+                tracing::trace!(frame = frame.frame, "Selected frame without a scene score");
+                continue;
+            };
+            if cuts
+                .last()
+                .is_some_and(|last: &SceneCut| cut.timestamp < last.timestamp + options.min_gap)
+            {
+                tracing::trace!(timestamp = ?cut.timestamp, "Dropping cut inside minimum gap");
+                continue;
+            }
+
+            tracing::debug!(timestamp = ?cut.timestamp, score = cut.score, "Scene cut detected");
+            if let Some(cuts_tx) = &cuts_tx {
+                let _ = cuts_tx.send(cut).await.inspect_err(|e| {
+                    tracing::warn!(error = %e, "Failed to send scene cut to channel");
+                });
+            }
+            cuts.push(cut);
+        }
+        cuts
+    };
+
+    let (result, cuts) = tokio::join!(run, collect);
+    result?;
+
+    tracing::info!(cuts = cuts.len(), "Scene detection complete");
+
+    Ok(cuts)
+}
+
+const SCENE_SCORE_KEY: &str = "lavfi.scene_score";
+
+/// The cut a frame `select` let through, `None` without a timestamp or score
+pub(crate) fn scene_cut(frame: &FrameMetadata) -> Option<SceneCut> {
+    Some(SceneCut {
+        timestamp: frame.pts_time?,
+        score: frame.get_f64(SCENE_SCORE_KEY)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::metadata::FrameMetadataParser;
+
+    // select='gt(scene,0.3)',metadata=print:key=lavfi.scene_score:file='pipe\:1'
+    const OUTPUT: &str = "\
+frame:0    pts:150     pts_time:6.25
+lavfi.scene_score=0.456789
+frame:1    pts:1302    pts_time:54.25
+lavfi.scene_score=0.912345";
+
+    #[test]
+    fn pairs_timestamps_with_scores() {
+        let mut parser = FrameMetadataParser::default();
+        let mut frames = OUTPUT
+            .lines()
+            .filter_map(|line| parser.push(line))
+            .collect::<Vec<_>>();
+        frames.extend(parser.finish());
+        assert_eq!(
+            frames.iter().filter_map(scene_cut).collect::<Vec<_>>(),
+            vec![
+                SceneCut {
+                    timestamp: Duration::from_secs_f64(6.25),
+                    score: 0.456_789,
+                },
+                SceneCut {
+                    timestamp: Duration::from_secs_f64(54.25),
+                    score: 0.912_345,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn frame_without_score_or_timestamp_is_skipped() {
+        let mut parser = FrameMetadataParser::default();
+        parser.push("frame:0    pts:NOPTS   pts_time:NOPTS");
+        parser.push("lavfi.scene_score=0.5");
+        let without_timestamp = parser.push("frame:1    pts:1302    pts_time:54.25");
+        let without_score = parser.finish();
+        assert_eq!(without_timestamp.as_ref().and_then(scene_cut), None);
+        assert_eq!(without_score.as_ref().and_then(scene_cut), None);
+        assert!(without_timestamp.is_some() && without_score.is_some());
+    }
+}
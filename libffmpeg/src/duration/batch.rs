@@ -0,0 +1,90 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use derive_builder::Builder;
+use futures::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span};
+
+use super::{DurationError, ProbeCache, ProbeOptions, get_duration_with_options};
+
+/// Options for [`get_durations`]
+#[derive(Debug, Clone, Builder)]
+#[builder(default)]
+pub struct BatchProbeOptions {
+    /// Maximum number of ffprobe processes running at once
+    pub concurrency: usize,
+    /// Options for every ffprobe run, `timeout` is applied to each file individually
+    pub probe: ProbeOptions,
+    /// Cache consulted before, and filled after, each probe
+    #[builder(setter(into, strip_option))]
+    pub cache: Option<Arc<ProbeCache>>,
+}
+
+impl Default for BatchProbeOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: std::thread::available_parallelism().map_or(4, std::num::NonZero::get),
+            probe: ProbeOptions::default(),
+            cache: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchProbeResult {
+    pub input: PathBuf,
+    pub result: Result<Duration, DurationError>,
+}
+
+/// Probe the duration of every input, running at most `options.concurrency` ffprobes at once.
+///
+/// Results are yielded in completion order, not input order. Every input yields exactly one result,
+/// once `cancellation_token` is cancelled the remaining inputs resolve to [`DurationError::Cancelled`]
+/// without spawning ffprobe.
+pub fn get_durations<I>(
+    inputs: I,
+    options: BatchProbeOptions,
+    cancellation_token: CancellationToken,
+) -> impl Stream<Item = BatchProbeResult>
+where
+    I: IntoIterator,
+    I::Item: Into<PathBuf>,
+{
+    let BatchProbeOptions {
+        concurrency,
+        probe,
+        cache,
+    } = options;
+    let probe = Arc::new(probe);
+    let parent_span = Span::current();
+
+    tracing::debug!(
+        concurrency = concurrency,
+        "Starting batch duration extraction"
+    );
+
+    futures::stream::iter(inputs.into_iter().map(Into::into))
+        .map(move |input: PathBuf| {
+            let probe = Arc::clone(&probe);
+            let cache = cache.clone();
+            let cancellation_token = cancellation_token.clone();
+            let span = tracing::debug_span!(parent: &parent_span, "batch_probe", input_path = %input.display());
+            async move {
+                if cancellation_token.is_cancelled() {
+                    tracing::trace!("Batch cancelled, skipping input");
+                    return BatchProbeResult {
+                        input,
+                        result: Err(DurationError::Cancelled),
+                    };
+                }
+
+                let result = match cache {
+                    Some(cache) => cache.get_duration(&input, &probe, cancellation_token).await,
+                    None => get_duration_with_options(&input, &probe, cancellation_token).await,
+                };
+                BatchProbeResult { input, result }
+            }
+            .instrument(span)
+        })
+        .buffer_unordered(concurrency.max(1))
+}
@@ -0,0 +1,536 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use derive_builder::Builder;
+use liberror::AnyError;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use super::{DurationError, ProbeOptions, get_duration_with_options};
+
+/// Bytes hashed from each end of a file when [`ProbeCacheOptions::content_hash`] is enabled
+const CONTENT_HASH_SAMPLE_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Valuable, Error)]
+pub enum ProbeCacheError {
+    #[error("Failed to read metadata for '{path}': {inner_error}")]
+    Metadata { path: String, inner_error: AnyError },
+    #[error("Failed to canonicalize '{path}': {inner_error}")]
+    Canonicalize { path: String, inner_error: AnyError },
+    #[error("Failed to hash contents of '{path}': {inner_error}")]
+    ContentHash { path: String, inner_error: AnyError },
+    #[error("Failed to read cache store '{path}': {inner_error}")]
+    ReadStore { path: String, inner_error: AnyError },
+    #[error("Failed to write cache store '{path}': {inner_error}")]
+    WriteStore { path: String, inner_error: AnyError },
+    #[error("Failed to decode cache store '{path}': {inner_error}")]
+    Decode { path: String, inner_error: AnyError },
+    #[error("Failed to encode cache store: {inner_error}")]
+    Encode { inner_error: AnyError },
+}
+
+/// Identifies a file's contents well enough to reuse a previous probe result
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileIdentity {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// FNV-1a over the first and last [`CONTENT_HASH_SAMPLE_BYTES`] of the file, if enabled
+    pub content_hash: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Builder)]
+#[builder(default)]
+pub struct ProbeCacheOptions {
+    /// Maximum number of entries kept, least recently used entries are evicted first
+    pub capacity: usize,
+    /// Include a sampled content hash in the key, catches files rewritten with the same size and mtime
+    pub content_hash: bool,
+    /// JSON file the cache is loaded from and persisted to, `None` keeps it in memory only
+    #[builder(setter(into, strip_option))]
+    pub store: Option<PathBuf>,
+}
+
+impl Default for ProbeCacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 100_000,
+            content_hash: false,
+            store: None,
+        }
+    }
+}
+
+/// A file probed with a particular set of options, see [`options_key`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct CacheKey {
+    identity: FileIdentity,
+    options: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredEntry {
+    #[serde(flatten)]
+    key: CacheKey,
+    duration: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<CacheKey, (Duration, u64)>,
+    order: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &CacheKey) -> Option<Duration> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (duration, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, key.clone());
+        Some(*duration)
+    }
+
+    fn insert(&mut self, key: CacheKey, duration: Duration, capacity: usize) {
+        self.tick += 1;
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (duration, self.tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(self.tick, key);
+
+        while self.entries.len() > capacity {
+            let Some((_, evicted)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&evicted);
+        }
+    }
+}
+
+/// Cache of probed durations keyed by [`FileIdentity`] and the [`ProbeOptions`] that affect the
+/// result (everything but the log level and timeout)
+///
+/// Entries are never invalidated explicitly, a modified file simply produces a different key and the
+/// stale entry ages out of the LRU.
+#[derive(Debug)]
+pub struct ProbeCache {
+    options: ProbeCacheOptions,
+    lru: Mutex<Lru>,
+}
+
+impl ProbeCache {
+    /// Create an empty cache, use [`ProbeCache::open`] to load an existing store
+    #[must_use]
+    pub fn new(options: ProbeCacheOptions) -> Self {
+        Self {
+            options,
+            lru: Mutex::default(),
+        }
+    }
+
+    /// Create a cache, loading entries from `options.store` if it exists
+    #[instrument(skip_all, fields(store = ?options.store))]
+    pub async fn open(options: ProbeCacheOptions) -> Result<Self, ProbeCacheError> {
+        let cache = Self::new(options);
+        let Some(store) = &cache.options.store else {
+            return Ok(cache);
+        };
+
+        let contents = match tokio::fs::read(store).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!(store = %store.display(), "Cache store does not exist yet");
+                return Ok(cache);
+            }
+            Err(e) => {
+                return Err(ProbeCacheError::ReadStore {
+                    path: store.display().to_string(),
+                    inner_error: e.into(),
+                })
+                .inspect_err(|e| tracing::error!(error = %e, "Failed to read cache store"));
+            }
+        };
+
+        let entries = serde_json::from_slice::<Vec<StoredEntry>>(&contents)
+            .map_err(|e| ProbeCacheError::Decode {
+                path: store.display().to_string(),
+                inner_error: e.into(),
+            })
+            .inspect_err(|e| tracing::error!(error = %e, "Failed to decode cache store"))?;
+
+        tracing::info!(
+            store = %store.display(),
+            entries = entries.len(),
+            "Loaded probe cache"
+        );
+
+        {
+            let mut lru = cache.lock();
+            for entry in entries {
+                lru.insert(entry.key, entry.duration, cache.options.capacity);
+            }
+        }
+
+        Ok(cache)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        *self.lock() = Lru::default();
+    }
+
+    /// The duration cached for `identity` probed with `options`
+    #[must_use]
+    pub fn get(&self, identity: &FileIdentity, options: &ProbeOptions) -> Option<Duration> {
+        self.lock().get(&CacheKey {
+            identity: identity.clone(),
+            options: options_key(options),
+        })
+    }
+
+    pub fn insert(&self, identity: FileIdentity, options: &ProbeOptions, duration: Duration) {
+        let key = CacheKey {
+            identity,
+            options: options_key(options),
+        };
+        self.lock().insert(key, duration, self.options.capacity);
+    }
+
+    /// Build the cache key for `path` using this cache's options
+    #[instrument(skip(self, path), fields(input_path = %path.as_ref().display()))]
+    pub async fn identify<P: AsRef<Path>>(&self, path: P) -> Result<FileIdentity, ProbeCacheError> {
+        let path = path.as_ref();
+        let canonical =
+            tokio::fs::canonicalize(path)
+                .await
+                .map_err(|e| ProbeCacheError::Canonicalize {
+                    path: path.display().to_string(),
+                    inner_error: e.into(),
+                })?;
+
+        let metadata =
+            tokio::fs::metadata(&canonical)
+                .await
+                .map_err(|e| ProbeCacheError::Metadata {
+                    path: canonical.display().to_string(),
+                    inner_error: e.into(),
+                })?;
+        let modified = metadata.modified().map_err(|e| ProbeCacheError::Metadata {
+            path: canonical.display().to_string(),
+            inner_error: e.into(),
+        })?;
+
+        let content_hash = if self.options.content_hash {
+            Some(
+                content_hash(&canonical, metadata.len())
+                    .await
+                    .map_err(|e| ProbeCacheError::ContentHash {
+                        path: canonical.display().to_string(),
+                        inner_error: e.into(),
+                    })?,
+            )
+        } else {
+            None
+        };
+
+        Ok(FileIdentity {
+            path: canonical,
+            size: metadata.len(),
+            modified,
+            content_hash,
+        })
+    }
+
+    /// [`get_duration_with_options`], reusing a cached result when the file is unchanged.
+    ///
+    /// Only successful probes are cached. If the file can't be identified it is probed uncached,
+    /// which lets ffprobe report the actual problem.
+    #[instrument(skip(self, input, options, cancellation_token), fields(input_path = %input.as_ref().display()))]
+    pub async fn get_duration<P: AsRef<Path>>(
+        &self,
+        input: P,
+        options: &ProbeOptions,
+        cancellation_token: CancellationToken,
+    ) -> Result<Duration, DurationError> {
+        let identity = self
+            .identify(&input)
+            .await
+            .inspect_err(|e| {
+                tracing::warn!(error = %e, "Failed to identify input, probing without cache");
+            })
+            .ok();
+
+        if let Some(duration) = identity
+            .as_ref()
+            .and_then(|identity| self.get(identity, options))
+        {
+            tracing::debug!(duration_seconds = duration.as_secs_f64(), "Probe cache hit");
+            return Ok(duration);
+        }
+
+        tracing::debug!("Probe cache miss");
+        let duration = get_duration_with_options(&input, options, cancellation_token).await?;
+
+        if let Some(identity) = identity {
+            self.insert(identity, options, duration);
+        }
+
+        Ok(duration)
+    }
+
+    /// Write every entry to `options.store`, does nothing for in-memory caches
+    #[instrument(skip_all)]
+    pub async fn persist(&self) -> Result<(), ProbeCacheError> {
+        let Some(store) = &self.options.store else {
+            return Ok(());
+        };
+
+        let encoded = {
+            let lru = self.lock();
+            // Oldest first, so reloading into a smaller capacity keeps the most recently used
+            let entries = lru
+                .order
+                .values()
+                .filter_map(|key| {
+                    lru.entries.get(key).map(|(duration, _)| StoredEntry {
+                        key: key.clone(),
+                        duration: *duration,
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_vec(&entries).map_err(|e| ProbeCacheError::Encode {
+                inner_error: e.into(),
+            })?
+        };
+
+        // Write then rename so a crash mid-write doesn't clobber the previous store. The temp file is
+        // unique so concurrent persists (other tasks or processes) can't write over each other's
+        let mut temp_name = store.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            uuid::Uuid::new_v4().simple()
+        ));
+        let temp = store.with_file_name(temp_name);
+        let write_error = |e: std::io::Error| ProbeCacheError::WriteStore {
+            path: store.display().to_string(),
+            inner_error: e.into(),
+        };
+        tokio::fs::write(&temp, &encoded)
+            .await
+            .map_err(write_error)
+            .inspect_err(|e| tracing::error!(error = %e, "Failed to write probe cache"))?;
+        if let Err(e) = tokio::fs::rename(&temp, store).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(write_error(e))
+                .inspect_err(|e| tracing::error!(error = %e, "Failed to persist probe cache"));
+        }
+
+        tracing::info!(
+            store = %store.display(),
+            bytes = encoded.len(),
+            "Persisted probe cache"
+        );
+
+        Ok(())
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// FNV-1a over the options that change what ffprobe reports. Stable across processes, so persisted
+/// entries still match
+fn options_key(options: &ProbeOptions) -> u64 {
+    let ProbeOptions {
+        threads,
+        probesize,
+        analyzeduration,
+        format,
+        select_streams,
+        log_level: _,
+        timeout: _,
+    } = options;
+    let fingerprint = format!(
+        "threads={threads:?};probesize={probesize:?};analyzeduration={:?};format={format:?};select_streams={select_streams:?}",
+        analyzeduration.map(|duration| duration.as_micros())
+    );
+    fnv1a(FNV_OFFSET, fingerprint.as_bytes())
+}
+
+async fn content_hash(path: &Path, size: u64) -> std::io::Result<u64> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buffer = Vec::with_capacity(CONTENT_HASH_SAMPLE_BYTES as usize);
+    let mut hash = FNV_OFFSET;
+    let mut update = |bytes: &[u8]| hash = fnv1a(hash, bytes);
+
+    (&mut file)
+        .take(CONTENT_HASH_SAMPLE_BYTES)
+        .read_to_end(&mut buffer)
+        .await?;
+    update(&buffer);
+
+    if size > CONTENT_HASH_SAMPLE_BYTES {
+        buffer.clear();
+        let tail_start = size
+            .saturating_sub(CONTENT_HASH_SAMPLE_BYTES)
+            .max(CONTENT_HASH_SAMPLE_BYTES);
+        file.seek(SeekFrom::Start(tail_start)).await?;
+        file.read_to_end(&mut buffer).await?;
+        update(&buffer);
+    }
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            identity: FileIdentity {
+                path: PathBuf::from(name),
+                size: 1024,
+                modified: SystemTime::UNIX_EPOCH,
+                content_hash: None,
+            },
+            options: options_key(&ProbeOptions::default()),
+        }
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = Lru::default();
+        lru.insert(key("a"), Duration::from_secs(1), 2);
+        lru.insert(key("b"), Duration::from_secs(2), 2);
+        // Touch `a` so `b` is the oldest
+        assert_eq!(lru.get(&key("a")), Some(Duration::from_secs(1)));
+        lru.insert(key("c"), Duration::from_secs(3), 2);
+
+        assert_eq!(lru.get(&key("b")), None);
+        assert_eq!(lru.get(&key("a")), Some(Duration::from_secs(1)));
+        assert_eq!(lru.get(&key("c")), Some(Duration::from_secs(3)));
+        assert_eq!(lru.entries.len(), lru.order.len());
+    }
+
+    #[test]
+    fn lru_reinsert_replaces_entry() {
+        let mut lru = Lru::default();
+        lru.insert(key("a"), Duration::from_secs(1), 2);
+        lru.insert(key("a"), Duration::from_secs(5), 2);
+
+        assert_eq!(lru.entries.len(), 1);
+        assert_eq!(lru.order.len(), 1);
+        assert_eq!(lru.get(&key("a")), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn options_that_change_the_result_change_the_key() {
+        let default = ProbeOptions::default();
+        let probesize = ProbeOptions {
+            probesize: Some(50_000_000),
+            ..ProbeOptions::default()
+        };
+        let analyzeduration = ProbeOptions {
+            analyzeduration: Some(Duration::from_secs(30)),
+            ..ProbeOptions::default()
+        };
+        let timeout = ProbeOptions {
+            timeout: Some(Duration::from_secs(10)),
+            ..ProbeOptions::default()
+        };
+
+        assert_ne!(options_key(&default), options_key(&probesize));
+        assert_ne!(options_key(&default), options_key(&analyzeduration));
+        assert_eq!(options_key(&default), options_key(&timeout));
+    }
+
+    #[test]
+    fn cache_misses_for_other_options() {
+        let cache = ProbeCache::new(ProbeCacheOptions::default());
+        let identity = key("a").identity;
+        let probesize = ProbeOptions {
+            probesize: Some(50_000_000),
+            ..ProbeOptions::default()
+        };
+        cache.insert(
+            identity.clone(),
+            &ProbeOptions::default(),
+            Duration::from_secs(1),
+        );
+
+        assert_eq!(
+            cache.get(&identity, &ProbeOptions::default()),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(cache.get(&identity, &probesize), None);
+    }
+
+    #[tokio::test]
+    async fn persist_round_trips_through_store() {
+        let dir = std::env::temp_dir().join(format!("libffmpeg-cache-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let store = dir.join("probe-cache.json");
+        let options = ProbeCacheOptionsBuilder::default()
+            .store(&store)
+            .build()
+            .unwrap();
+
+        let cache = ProbeCache::new(options.clone());
+        let identity = key("a").identity;
+        cache.insert(
+            identity.clone(),
+            &ProbeOptions::default(),
+            Duration::from_millis(1500),
+        );
+        let (first, second) = tokio::join!(cache.persist(), cache.persist());
+        first.unwrap();
+        second.unwrap();
+
+        let reopened = ProbeCache::open(options).await.unwrap();
+        assert_eq!(
+            reopened.get(&identity, &ProbeOptions::default()),
+            Some(Duration::from_millis(1500))
+        );
+        // Only the store is left behind, no temp files
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, vec![std::ffi::OsString::from("probe-cache.json")]);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use valuable::Valuable;
+
+use super::DurationError;
+
+/// How many trailing stderr lines are kept on classified errors
+pub const STDERR_TAIL_LINES: usize = 20;
+
+/// Broad cause of an ffprobe failure, derived from its error output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Valuable)]
+pub enum ProbeFailureKind {
+    FileNotFound,
+    PermissionDenied,
+    InvalidData,
+    UnsupportedFormat,
+    Truncated,
+    ProtocolNotAllowed,
+    /// The input's protocol isn't built into this ffprobe (e.g. `https` without TLS support)
+    ProtocolNotFound,
+}
+
+// Checked in order, first match wins. Truncation shows up alongside "Invalid data", so it has to come first
+const PATTERNS: &[(&str, ProbeFailureKind)] = &[
+    ("No such file or directory", ProbeFailureKind::FileNotFound),
+    ("Permission denied", ProbeFailureKind::PermissionDenied),
+    (
+        "Operation not permitted",
+        ProbeFailureKind::PermissionDenied,
+    ),
+    ("not on whitelist", ProbeFailureKind::ProtocolNotAllowed),
+    ("Protocol not found", ProbeFailureKind::ProtocolNotFound),
+    ("moov atom not found", ProbeFailureKind::Truncated),
+    ("partial file", ProbeFailureKind::Truncated),
+    ("Truncating packet", ProbeFailureKind::Truncated),
+    ("End of file", ProbeFailureKind::Truncated),
+    ("Unknown input format", ProbeFailureKind::UnsupportedFormat),
+    (
+        "could not find codec parameters",
+        ProbeFailureKind::UnsupportedFormat,
+    ),
+    ("Invalid data found", ProbeFailureKind::InvalidData),
+];
+
+// Markers that, for piped input, mean the demuxer wanted to seek
+const SEEK_PATTERNS: &[&str] = &[
+    "moov atom not found",
+    "partial file",
+    "Illegal seek",
+    "seek failed",
+];
+
+/// Classify ffprobe's stderr, `None` if nothing recognisable was printed
+#[must_use]
+pub fn classify_probe_failure(stderr_lines: &[String]) -> Option<ProbeFailureKind> {
+    PATTERNS.iter().find_map(|(pattern, kind)| {
+        stderr_lines
+            .iter()
+            .any(|line| line.contains(pattern))
+            .then_some(*kind)
+    })
+}
+
+/// Last [`STDERR_TAIL_LINES`] non-empty lines of `stderr_lines`
+#[must_use]
+pub fn stderr_tail(stderr_lines: &[String]) -> Vec<String> {
+    let lines = stderr_lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..]
+        .iter()
+        .map(|line| (*line).clone())
+        .collect()
+}
+
+/// Whether ffprobe's stderr indicates it failed because it couldn't seek in a piped input
+pub(crate) fn requires_seek(stderr_lines: &[String]) -> bool {
+    SEEK_PATTERNS
+        .iter()
+        .any(|pattern| stderr_lines.iter().any(|line| line.contains(pattern)))
+}
+
+/// Build the most specific [`DurationError`] for an ffprobe that exited unsuccessfully
+pub(crate) fn failure_error(code: Option<i32>, stderr_lines: &[String]) -> DurationError {
+    let stderr_tail = stderr_tail(stderr_lines);
+    match classify_probe_failure(stderr_lines) {
+        Some(ProbeFailureKind::FileNotFound) => DurationError::FileNotFound { stderr_tail },
+        Some(ProbeFailureKind::PermissionDenied) => DurationError::PermissionDenied { stderr_tail },
+        Some(ProbeFailureKind::InvalidData) => DurationError::InvalidData { stderr_tail },
+        Some(ProbeFailureKind::UnsupportedFormat) => {
+            DurationError::UnsupportedFormat { stderr_tail }
+        }
+        Some(ProbeFailureKind::Truncated) => DurationError::Truncated { stderr_tail },
+        Some(ProbeFailureKind::ProtocolNotAllowed) => {
+            DurationError::ProtocolNotAllowed { stderr_tail }
+        }
+        Some(ProbeFailureKind::ProtocolNotFound) => DurationError::ProtocolNotFound { stderr_tail },
+        None => DurationError::ExitedUnsuccessfully { code, stderr_tail },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(stderr: &str) -> Vec<String> {
+        stderr.lines().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn classifies_missing_file() {
+        let stderr = lines("missing.mp4: No such file or directory");
+        assert_eq!(
+            classify_probe_failure(&stderr),
+            Some(ProbeFailureKind::FileNotFound)
+        );
+    }
+
+    #[test]
+    fn classifies_truncated_before_invalid_data() {
+        let stderr = lines(
+            "[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55d0c6f0a2c0] moov atom not found\n\
+             truncated.mp4: Invalid data found when processing input",
+        );
+        assert_eq!(
+            classify_probe_failure(&stderr),
+            Some(ProbeFailureKind::Truncated)
+        );
+    }
+
+    #[test]
+    fn classifies_missing_protocol_separately_from_format() {
+        let stderr = lines("srt://127.0.0.1:9000: Protocol not found");
+        assert_eq!(
+            classify_probe_failure(&stderr),
+            Some(ProbeFailureKind::ProtocolNotFound)
+        );
+        assert!(matches!(
+            failure_error(Some(1), &stderr),
+            DurationError::ProtocolNotFound { .. }
+        ));
+    }
+
+    #[test]
+    fn classifies_whitelist_rejection() {
+        let stderr = lines(
+            "[file @ 0x5581a1b1e640] Protocol 'file' not on whitelist 'crypto,data'!\n\
+             playlist.m3u8: Invalid argument",
+        );
+        assert_eq!(
+            classify_probe_failure(&stderr),
+            Some(ProbeFailureKind::ProtocolNotAllowed)
+        );
+    }
+
+    #[test]
+    fn unrecognised_failure_keeps_exit_code() {
+        let stderr = lines("Something unexpected happened");
+        assert_eq!(classify_probe_failure(&stderr), None);
+        assert!(matches!(
+            failure_error(Some(1), &stderr),
+            DurationError::ExitedUnsuccessfully { code: Some(1), .. }
+        ));
+    }
+
+    #[test]
+    fn tail_skips_blank_lines_and_keeps_the_last() {
+        let stderr = (0..30)
+            .flat_map(|i| [format!("line {i}"), String::new()])
+            .collect::<Vec<_>>();
+        let tail = stderr_tail(&stderr);
+        assert_eq!(tail.len(), STDERR_TAIL_LINES);
+        assert_eq!(tail.first().map(String::as_str), Some("line 10"));
+        assert_eq!(tail.last().map(String::as_str), Some("line 29"));
+    }
+}
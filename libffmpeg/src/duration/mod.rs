@@ -0,0 +1,290 @@
+pub mod batch;
+pub mod cache;
+pub mod classify;
+pub mod options;
+pub mod pipe;
+
+use std::{path::Path, time::Duration};
+
+use liberror::AnyError;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use valuable::Valuable;
+
+use crate::env::find::{FindBinaryError, find_binary_env};
+
+use libcmd::{CommandError, CommandExit};
+
+pub use batch::{BatchProbeOptions, BatchProbeOptionsBuilder, BatchProbeResult, get_durations};
+pub use cache::{
+    FileIdentity, ProbeCache, ProbeCacheError, ProbeCacheOptions, ProbeCacheOptionsBuilder,
+};
+pub use classify::{ProbeFailureKind, classify_probe_failure};
+pub use options::{ProbeOptions, ProbeOptionsBuilder, ProbeOptionsBuilderError};
+pub use pipe::{
+    ProbeOutput, get_duration_from_bytes, get_duration_from_reader, probe_from_bytes,
+    probe_from_reader,
+};
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize, Valuable)]
+pub enum DurationError {
+    #[error(transparent)]
+    Command {
+        #[from]
+        inner_error: CommandError,
+    },
+    #[error(transparent)]
+    FindBinary {
+        #[from]
+        inner_error: FindBinaryError,
+    },
+
+    #[error("Process returned, but no exit status was present: stdout_lines={}, stderr_lines={}", result.stdout_lines.len(), result.stderr_lines.len())]
+    IncompleteSubprocess { result: CommandExit },
+    #[error("ffprobe exited unsuccessfully with code {}: {}", code.map_or_else(|| "unknown".to_string(), |c| c.to_string()), stderr_tail.join("\n"))]
+    ExitedUnsuccessfully {
+        code: Option<i32>,
+        stderr_tail: Vec<String>,
+    },
+    #[error("ffprobe could not find the input: {}", stderr_tail.join("\n"))]
+    FileNotFound { stderr_tail: Vec<String> },
+    #[error("ffprobe was not permitted to read the input: {}", stderr_tail.join("\n"))]
+    PermissionDenied { stderr_tail: Vec<String> },
+    #[error("ffprobe found invalid data in the input: {}", stderr_tail.join("\n"))]
+    InvalidData { stderr_tail: Vec<String> },
+    #[error("ffprobe does not support the input format: {}", stderr_tail.join("\n"))]
+    UnsupportedFormat { stderr_tail: Vec<String> },
+    #[error("Input appears to be truncated: {}", stderr_tail.join("\n"))]
+    Truncated { stderr_tail: Vec<String> },
+    #[error("ffprobe refused to open the input protocol: {}", stderr_tail.join("\n"))]
+    ProtocolNotAllowed { stderr_tail: Vec<String> },
+    #[error("ffprobe does not support the input protocol: {}", stderr_tail.join("\n"))]
+    ProtocolNotFound { stderr_tail: Vec<String> },
+    #[error("Expected ffprobe to output a line with the duration, got {} stdout lines and {} stderr lines: {}", result.stdout_lines.len(), result.stderr_lines.len(), result.stdout_lines.join("\n"))]
+    ExpectedLine { result: CommandExit },
+    #[error("Failed to parse duration provided by ffprobe: {inner_error}")]
+    Parse { inner_error: AnyError },
+    #[error(
+        "Unable to locate ffprobe on your PATH, set LIBFFMPEG_FFPROBE_PATH to the binary, or update your PATH"
+    )]
+    FfprobeNotFound,
+    #[error("ffprobe did not finish within {timeout_ms}ms")]
+    TimedOut { timeout_ms: u64 },
+    #[error("ffprobe was cancelled before it finished")]
+    Cancelled,
+    #[error("Input needs to be seekable for ffprobe to determine its duration (e.g. MP4 with the moov atom at the end), write it to a file first: {}", stderr_tail.join("\n"))]
+    RequiresSeekableInput { stderr_tail: Vec<String> },
+    #[error("Failed to spawn ffprobe: {inner_error}")]
+    Spawn { inner_error: AnyError },
+    #[error("Failed to read input to feed ffprobe: {inner_error}")]
+    ReadInput { inner_error: AnyError },
+    #[error("Failed to communicate with ffprobe: {inner_error}")]
+    Pipe { inner_error: AnyError },
+}
+
+impl DurationError {
+    /// The classified cause of an unsuccessful ffprobe run, if there was one
+    #[must_use]
+    pub fn failure_kind(&self) -> Option<ProbeFailureKind> {
+        match self {
+            Self::FileNotFound { .. } => Some(ProbeFailureKind::FileNotFound),
+            Self::PermissionDenied { .. } => Some(ProbeFailureKind::PermissionDenied),
+            Self::InvalidData { .. } => Some(ProbeFailureKind::InvalidData),
+            Self::UnsupportedFormat { .. } => Some(ProbeFailureKind::UnsupportedFormat),
+            Self::Truncated { .. } => Some(ProbeFailureKind::Truncated),
+            Self::ProtocolNotAllowed { .. } => Some(ProbeFailureKind::ProtocolNotAllowed),
+            Self::ProtocolNotFound { .. } => Some(ProbeFailureKind::ProtocolNotFound),
+            _ => None,
+        }
+    }
+
+    /// Trailing ffprobe stderr attached to the error, empty if there was none
+    #[must_use]
+    pub fn stderr_tail(&self) -> &[String] {
+        match self {
+            Self::ExitedUnsuccessfully { stderr_tail, .. }
+            | Self::FileNotFound { stderr_tail }
+            | Self::PermissionDenied { stderr_tail }
+            | Self::InvalidData { stderr_tail }
+            | Self::UnsupportedFormat { stderr_tail }
+            | Self::Truncated { stderr_tail }
+            | Self::ProtocolNotAllowed { stderr_tail }
+            | Self::ProtocolNotFound { stderr_tail }
+            | Self::RequiresSeekableInput { stderr_tail } => stderr_tail,
+            _ => &[],
+        }
+    }
+}
+
+/// Equivalent to [`get_duration_with_options`] with [`ProbeOptions::default`]
+pub async fn get_duration<P: AsRef<Path>>(
+    input: P,
+    cancellation_token: CancellationToken,
+) -> Result<Duration, DurationError> {
+    get_duration_with_options(input, &ProbeOptions::default(), cancellation_token).await
+}
+
+#[instrument(skip(input, options, cancellation_token), fields(input_path = %input.as_ref().display()))]
+#[allow(clippy::too_many_lines)]
+pub async fn get_duration_with_options<P: AsRef<Path>>(
+    input: P,
+    options: &ProbeOptions,
+    cancellation_token: CancellationToken,
+) -> Result<Duration, DurationError> {
+    tracing::debug!(
+        input_path = %input.as_ref().display(),
+        options = ?options,
+        "Starting duration extraction"
+    );
+
+    let Some(ffprobe_path) = find_binary_env("ffprobe").await.inspect_err(|e| {
+        tracing::error!(
+            error = %e,
+            "Failed to search for ffprobe binary"
+        );
+    })?
+    else {
+        tracing::error!("ffprobe binary not found");
+        return Err(DurationError::FfprobeNotFound);
+    };
+
+    tracing::info!(
+        ffprobe_path = %ffprobe_path.display(),
+        input_path = %input.as_ref().display(),
+        "Executing ffprobe to get duration"
+    );
+
+    let process_token = cancellation_token.child_token();
+    let run = libcmd::run(ffprobe_path, None, process_token.clone(), |cmd| {
+        options.apply(cmd);
+        cmd.arg("-show_entries").arg("format=duration");
+        cmd.arg("-of").arg("default=noprint_wrappers=1:nokey=1");
+        cmd.arg(input.as_ref());
+    });
+    tokio::pin!(run);
+
+    let result = match options.timeout {
+        Some(timeout) => {
+            tokio::select! {
+                result = &mut run => result,
+                () = tokio::time::sleep(timeout) => {
+                    tracing::error!(
+                        timeout_ms = timeout.as_millis() as u64,
+                        "ffprobe timed out, cancelling"
+                    );
+                    // Let libcmd tear the process down before returning
+                    process_token.cancel();
+                    let _ = run.await;
+                    return Err(DurationError::TimedOut {
+                        timeout_ms: timeout.as_millis() as u64,
+                    });
+                }
+            }
+        }
+        None => run.await,
+    };
+
+    if stopped_by_token(&result, &cancellation_token) {
+        tracing::debug!("ffprobe cancelled");
+        return Err(DurationError::Cancelled);
+    }
+
+    let mut result = result
+        .inspect(|exit| {
+            tracing::debug!(
+                exit_code = ?exit.exit_code,
+                stdout_lines = exit.stdout_lines.len(),
+                stderr_lines = exit.stderr_lines.len(),
+                "ffprobe completed"
+            );
+        })
+        .inspect_err(|e| {
+            tracing::error!(
+                error = %e,
+                "ffprobe execution failed"
+            );
+        })?;
+
+    let Some(exit_code) = result.exit_code.take() else {
+        tracing::error!(
+            stdout_lines = result.stdout_lines.len(),
+            stderr_lines = result.stderr_lines.len(),
+            "Process returned but no exit status was present"
+        );
+        return Err(DurationError::IncompleteSubprocess { result });
+    };
+
+    if !exit_code.success {
+        let error = classify::failure_error(exit_code.code, &result.stderr_lines);
+        tracing::error!(
+            exit_code = ?exit_code,
+            failure_kind = ?error.failure_kind(),
+            stderr_lines = ?result.stderr_lines,
+            "ffprobe exited unsuccessfully"
+        );
+        return Err(error);
+    }
+
+    let Some(duration_line) = result.stdout_lines.first() else {
+        tracing::error!(
+            stdout_lines = ?result.stdout_lines,
+            stderr_lines = ?result.stderr_lines,
+            "Expected ffprobe to output a line with the duration"
+        );
+        return Err(DurationError::ExpectedLine { result });
+    };
+
+    parse_duration_line(duration_line)
+}
+
+/// Whether cancelling `cancellation_token` cut a libcmd run short. libcmd kills the process once the
+/// token is cancelled but returns the exit as usual, so a run that succeeded before the token was
+/// cancelled is kept
+pub(crate) fn stopped_by_token(
+    result: &Result<CommandExit, CommandError>,
+    cancellation_token: &CancellationToken,
+) -> bool {
+    let succeeded = result.as_ref().is_ok_and(|exit| {
+        exit.exit_code
+            .as_ref()
+            .is_some_and(|exit_code| exit_code.success)
+    });
+    cancellation_token.is_cancelled() && !succeeded
+}
+
+pub(crate) fn parse_duration_line(duration_line: &str) -> Result<Duration, DurationError> {
+    tracing::trace!(
+        duration_line = %duration_line,
+        "Parsing duration from ffprobe output"
+    );
+
+    let duration_seconds = duration_line
+        .parse::<f64>()
+        .map_err(|e| {
+            tracing::error!(
+                duration_line = %duration_line,
+                error = %e,
+                "Failed to parse duration from ffprobe output"
+            );
+            DurationError::Parse {
+                inner_error: e.into(),
+            }
+        })
+        .inspect(|seconds| {
+            tracing::trace!(
+                duration_seconds = %seconds,
+                "Successfully parsed duration"
+            );
+        })?;
+
+    let duration = Duration::from_secs_f64(duration_seconds);
+
+    tracing::info!(
+        duration_seconds = %duration_seconds,
+        "Successfully extracted duration"
+    );
+
+    Ok(duration)
+}
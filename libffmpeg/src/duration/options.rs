@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::log::LogLevel;
+
+/// Input and runtime options for ffprobe invocations
+///
+/// ```ignore
+/// let options = ProbeOptionsBuilder::default()
+///     .probesize(50_000_000u64)
+///     .analyzeduration(Duration::from_secs(30))
+///     .format("mpegts")
+///     .timeout(Duration::from_secs(10))
+///     .build()?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct ProbeOptions {
+    /// `-threads`, `None` lets ffprobe decide
+    #[builder(setter(into, strip_option))]
+    pub threads: Option<u32>,
+    /// `-probesize`, in bytes
+    #[builder(setter(into, strip_option))]
+    pub probesize: Option<u64>,
+    /// `-analyzeduration`, passed to ffprobe in microseconds
+    #[builder(setter(into, strip_option))]
+    pub analyzeduration: Option<Duration>,
+    /// `-f`, forces the input format instead of relying on detection
+    #[builder(setter(into, strip_option))]
+    pub format: Option<String>,
+    /// `-select_streams`, e.g. `v:0` or `a`
+    #[builder(setter(into, strip_option))]
+    pub select_streams: Option<String>,
+    /// `-v`, anything quieter than [`LogLevel::Error`] leaves failures unclassified
+    pub log_level: LogLevel,
+    /// Maximum wall-clock time for a single ffprobe run, the process is killed once this elapses
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        Self {
+            threads: Some(4),
+            probesize: None,
+            analyzeduration: None,
+            format: None,
+            select_streams: None,
+            log_level: LogLevel::Error,
+            timeout: None,
+        }
+    }
+}
+
+impl ProbeOptions {
+    /// NOTE: These are input options, call this BEFORE adding the input to `cmd`
+    pub(crate) fn apply(&self, cmd: &mut Command) {
+        if let Some(threads) = self.threads {
+            cmd.arg("-threads").arg(threads.to_string());
+        }
+        cmd.arg("-v").arg(self.log_level.to_string());
+        if let Some(probesize) = self.probesize {
+            cmd.arg("-probesize").arg(probesize.to_string());
+        }
+        if let Some(analyzeduration) = self.analyzeduration {
+            cmd.arg("-analyzeduration")
+                .arg(analyzeduration.as_micros().to_string());
+        }
+        if let Some(format) = &self.format {
+            cmd.arg("-f").arg(format);
+        }
+        if let Some(select_streams) = &self.select_streams {
+            cmd.arg("-select_streams").arg(select_streams);
+        }
+    }
+}
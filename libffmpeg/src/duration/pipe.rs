@@ -0,0 +1,239 @@
+use std::{process::Stdio, time::Duration};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use super::{DurationError, ProbeOptions, classify, parse_duration_line};
+use crate::process;
+
+const FEED_BUFFER_BYTES: usize = 64 * 1024;
+
+/// What a successful ffprobe run over piped input printed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeOutput {
+    pub stdout_lines: Vec<String>,
+    pub stderr_lines: Vec<String>,
+}
+
+/// [`get_duration_from_reader`] over an in-memory buffer
+pub async fn get_duration_from_bytes(
+    input: Bytes,
+    options: &ProbeOptions,
+    cancellation_token: CancellationToken,
+) -> Result<Duration, DurationError> {
+    get_duration_from_reader(std::io::Cursor::new(input), options, cancellation_token).await
+}
+
+/// Probe the duration of a stream by feeding it to ffprobe's stdin (`pipe:0`).
+///
+/// Formats that need to seek to find their duration (MP4/MOV with the moov atom at the end, most
+/// notably) can't be probed this way and fail with [`DurationError::RequiresSeekableInput`], as does
+/// any input ffprobe can't find a duration for without reading to the end. See [`probe_from_reader`]
+/// for how much of `input` is read.
+#[instrument(skip_all)]
+pub async fn get_duration_from_reader<R: AsyncRead + Unpin>(
+    input: R,
+    options: &ProbeOptions,
+    cancellation_token: CancellationToken,
+) -> Result<Duration, DurationError> {
+    let output = probe_from_reader(input, options, cancellation_token, |cmd| {
+        cmd.arg("-show_entries").arg("format=duration");
+        cmd.arg("-of").arg("default=noprint_wrappers=1:nokey=1");
+    })
+    .await?;
+
+    match output.stdout_lines.first().map(|line| line.trim()) {
+        Some(line) if !line.is_empty() && line != "N/A" => parse_duration_line(line),
+        _ => {
+            tracing::error!(
+                stdout_lines = ?output.stdout_lines,
+                "ffprobe could not determine a duration from piped input"
+            );
+            Err(DurationError::RequiresSeekableInput {
+                stderr_tail: classify::stderr_tail(&output.stderr_lines),
+            })
+        }
+    }
+}
+
+/// [`probe_from_reader`] over an in-memory buffer
+pub async fn probe_from_bytes<Prepare>(
+    input: Bytes,
+    options: &ProbeOptions,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<ProbeOutput, DurationError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    probe_from_reader(
+        std::io::Cursor::new(input),
+        options,
+        cancellation_token,
+        prepare,
+    )
+    .await
+}
+
+/// Run ffprobe over a stream fed to its stdin (`pipe:0`), e.g. with `-show_streams -of json`.
+///
+/// `input` is fed until it ends, ffprobe closes its stdin, or ffprobe exits. ffprobe normally stops
+/// once it has what it needs, leaving the rest of `input` unread, except for the chunk (up to 64KiB)
+/// being fed at the time, which is discarded.
+///
+/// Failures are classified like [`super::get_duration_with_options`], with
+/// [`DurationError::RequiresSeekableInput`] for inputs ffprobe wanted to seek in.
+///
+/// NOTE: `options` are applied before `prepare`, and `pipe:0` is added after it
+#[instrument(skip_all)]
+#[allow(clippy::too_many_lines)]
+pub async fn probe_from_reader<R, Prepare>(
+    mut input: R,
+    options: &ProbeOptions,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<ProbeOutput, DurationError>
+where
+    R: AsyncRead + Unpin,
+    Prepare: FnOnce(&mut Command),
+{
+    tracing::debug!(options = ?options, "Starting piped probe");
+
+    // Not run through libcmd: its monitor can only write text lines to stdin, and the input is
+    // arbitrary bytes that have to be streamed while ffprobe runs
+    let (mut child, ffprobe_path) = process::spawn("ffprobe", Stdio::piped(), |cmd| {
+        options.apply(cmd);
+        prepare(cmd);
+        cmd.arg("pipe:0");
+    })
+    .await?;
+
+    tracing::info!(
+        ffprobe_path = %ffprobe_path.display(),
+        "Executing ffprobe on piped input"
+    );
+
+    let (Some(mut stdin), Some(stdout), Some(stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        unreachable!("stdin, stdout and stderr are piped")
+    };
+
+    let feed = async move {
+        let mut buffer = vec![0u8; FEED_BUFFER_BYTES];
+        loop {
+            let read = input
+                .read(&mut buffer)
+                .await
+                .map_err(|e| DurationError::ReadInput {
+                    inner_error: e.into(),
+                })?;
+            if read == 0 {
+                tracing::trace!("Finished feeding ffprobe");
+                break;
+            }
+            match stdin.write_all(&buffer[..read]).await {
+                Ok(()) => {}
+                // ffprobe closes stdin as soon as it has read enough, that's not a failure
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    tracing::trace!("ffprobe closed stdin");
+                    break;
+                }
+                Err(e) => {
+                    return Err(DurationError::Pipe {
+                        inner_error: e.into(),
+                    });
+                }
+            }
+        }
+        // `stdin` is dropped here, giving ffprobe its EOF
+        Ok(())
+    };
+    let timeout = async {
+        match options.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let outcome = {
+        let wait = async {
+            tokio::join!(
+                process::read_lines(stdout),
+                process::read_lines(stderr),
+                child.wait()
+            )
+        };
+        tokio::pin!(feed, wait, timeout);
+
+        let mut feed_done = false;
+        loop {
+            tokio::select! {
+                output = &mut wait => break Ok(output),
+                result = &mut feed, if !feed_done => {
+                    feed_done = true;
+                    if let Err(e) = result {
+                        tracing::error!(error = %e, "Failed to feed ffprobe");
+                        break Err(e);
+                    }
+                }
+                () = cancellation_token.cancelled() => {
+                    tracing::debug!("ffprobe cancelled");
+                    break Err(DurationError::Cancelled);
+                }
+                () = &mut timeout => {
+                    let timeout_ms = options.timeout.map_or(0, |t| t.as_millis() as u64);
+                    tracing::error!(timeout_ms = timeout_ms, "ffprobe timed out, cancelling");
+                    break Err(DurationError::TimedOut { timeout_ms });
+                }
+            }
+        }
+    };
+    let (stdout_lines, stderr_lines, status) = match outcome {
+        Ok(output) => output,
+        Err(e) => {
+            let _ = process::kill(&mut child).await;
+            return Err(e);
+        }
+    };
+
+    let status = status
+        .map_err(|e| DurationError::Pipe {
+            inner_error: e.into(),
+        })
+        .inspect_err(|e| tracing::error!(error = %e, "Failed to wait for ffprobe"))?;
+
+    tracing::debug!(
+        status = %status,
+        stdout_lines = stdout_lines.len(),
+        stderr_lines = stderr_lines.len(),
+        "ffprobe completed"
+    );
+
+    if !status.success() {
+        let error = if classify::requires_seek(&stderr_lines) {
+            DurationError::RequiresSeekableInput {
+                stderr_tail: classify::stderr_tail(&stderr_lines),
+            }
+        } else {
+            classify::failure_error(status.code(), &stderr_lines)
+        };
+        tracing::error!(
+            status = %status,
+            error = %error,
+            "ffprobe exited unsuccessfully"
+        );
+        return Err(error);
+    }
+
+    Ok(ProbeOutput {
+        stdout_lines,
+        stderr_lines,
+    })
+}
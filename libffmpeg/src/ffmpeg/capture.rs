@@ -0,0 +1,242 @@
+use std::{collections::VecDeque, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use super::FfmpegError;
+use crate::duration::classify::STDERR_TAIL_LINES;
+
+/// Which of ffmpeg's stderr lines a job keeps in [`super::FfmpegExit::stderr_lines`]. Every line
+/// still goes through progress and log parsing and failure classification, and the last few are
+/// always kept for errors.
+///
+/// Applies to every runner taking [`super::FfmpegJobOptions`], they all run ffmpeg as a
+/// [`super::FfmpegJob`]. [`super::ffmpeg_graceful`] and [`super::ffmpeg_graceful_raw`] run through
+/// libcmd with a caller's monitor and keep every line until ffmpeg exits
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapturePolicy {
+    /// Keep every line, grows without bound on long runs
+    #[default]
+    All,
+    /// Keep the last `n` lines
+    Tail(usize),
+    /// Keep the first `head` and last `tail` lines
+    HeadTail { head: usize, tail: usize },
+    /// Keep nothing
+    Discard,
+    /// Append every line to a file instead of keeping them, the file is created if needed
+    File(PathBuf),
+}
+
+/// Applies a [`CapturePolicy`] to lines as they arrive
+#[derive(Debug)]
+pub(crate) struct Capture {
+    head_limit: usize,
+    tail_limit: Option<usize>,
+    head: Vec<String>,
+    tail: VecDeque<String>,
+    dropped: usize,
+    file: Option<BufWriter<File>>,
+    /// Last non-empty lines, kept whatever the policy for error reporting
+    recent: VecDeque<String>,
+}
+
+/// Lines kept by a [`Capture`]
+#[derive(Debug)]
+pub(crate) struct Captured {
+    pub(crate) lines: Vec<String>,
+    pub(crate) dropped: usize,
+    pub(crate) recent: Vec<String>,
+}
+
+impl Capture {
+    pub(crate) async fn new(policy: &CapturePolicy) -> Result<Self, FfmpegError> {
+        let (head_limit, tail_limit) = match policy {
+            CapturePolicy::All => (0, None),
+            CapturePolicy::Tail(tail) => (0, Some(*tail)),
+            CapturePolicy::HeadTail { head, tail } => (*head, Some(*tail)),
+            CapturePolicy::Discard | CapturePolicy::File(_) => (0, Some(0)),
+        };
+
+        let file = match policy {
+            CapturePolicy::File(path) => {
+                let file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(|e| FfmpegError::CaptureFile {
+                        path: path.display().to_string(),
+                        inner_error: e.into(),
+                    })
+                    .inspect_err(|e| tracing::error!(error = %e, "Failed to open capture file"))?;
+                Some(BufWriter::new(file))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            head_limit,
+            tail_limit,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            dropped: 0,
+            file,
+            recent: VecDeque::new(),
+        })
+    }
+
+    pub(crate) async fn push(&mut self, line: String) {
+        if !line.trim().is_empty() {
+            if self.recent.len() == STDERR_TAIL_LINES {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(line.clone());
+        }
+
+        if let Some(file) = &mut self.file {
+            let written = async {
+                file.write_all(line.as_bytes()).await?;
+                file.write_all(b"\n").await
+            };
+            if let Err(e) = written.await {
+                tracing::warn!(error = %e, "Failed to write to capture file, discarding the rest");
+                self.file = None;
+            }
+        }
+
+        if self.head.len() < self.head_limit {
+            self.head.push(line);
+            return;
+        }
+        match self.tail_limit {
+            None => self.tail.push_back(line),
+            Some(0) => self.dropped += 1,
+            Some(limit) => {
+                if self.tail.len() == limit {
+                    self.tail.pop_front();
+                    self.dropped += 1;
+                }
+                self.tail.push_back(line);
+            }
+        }
+    }
+
+    pub(crate) async fn finish(mut self) -> Captured {
+        if let Some(file) = &mut self.file {
+            let _ = file.flush().await.inspect_err(|e| {
+                tracing::warn!(error = %e, "Failed to flush capture file");
+            });
+        }
+
+        let mut lines = self.head;
+        lines.extend(self.tail);
+        Captured {
+            lines,
+            dropped: self.dropped,
+            recent: self.recent.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn capture(policy: &CapturePolicy, lines: usize) -> Captured {
+        let mut capture = Capture::new(policy).await.expect("capture");
+        for frame in 0..lines {
+            capture
+                .push(format!(
+                    "[h264 @ 0x55c1e0d3a140] error while decoding MB {frame} 20"
+                ))
+                .await;
+        }
+        capture.finish().await
+    }
+
+    fn frames(captured: &Captured) -> Vec<usize> {
+        captured
+            .lines
+            .iter()
+            .filter_map(|line| line.split_whitespace().nth(7)?.parse().ok())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn all_keeps_every_line() {
+        let captured = capture(&CapturePolicy::All, 30).await;
+        assert_eq!(frames(&captured), (0..30).collect::<Vec<_>>());
+        assert_eq!(captured.dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn tail_keeps_the_last_lines() {
+        let captured = capture(&CapturePolicy::Tail(3), 10).await;
+        assert_eq!((frames(&captured), captured.dropped), (vec![7, 8, 9], 7));
+    }
+
+    #[tokio::test]
+    async fn head_tail_drops_the_middle() {
+        let captured = capture(&CapturePolicy::HeadTail { head: 2, tail: 2 }, 10).await;
+        assert_eq!((frames(&captured), captured.dropped), (vec![0, 1, 8, 9], 6));
+
+        let short = capture(&CapturePolicy::HeadTail { head: 2, tail: 2 }, 3).await;
+        assert_eq!((frames(&short), short.dropped), (vec![0, 1, 2], 0));
+    }
+
+    #[tokio::test]
+    async fn discard_still_keeps_recent_lines() {
+        let mut capture = Capture::new(&CapturePolicy::Discard)
+            .await
+            .expect("capture");
+        for frame in 0..STDERR_TAIL_LINES + 5 {
+            capture.push(format!("frame={frame}")).await;
+            capture.push(String::new()).await;
+        }
+        let captured = capture.finish().await;
+        assert!(captured.lines.is_empty());
+        assert_eq!(captured.dropped, (STDERR_TAIL_LINES + 5) * 2);
+        assert_eq!(captured.recent.len(), STDERR_TAIL_LINES);
+        assert_eq!(captured.recent.first().map(String::as_str), Some("frame=5"));
+    }
+
+    #[tokio::test]
+    async fn file_appends_every_line() {
+        let path =
+            std::env::temp_dir().join(format!("libffmpeg-capture-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        tokio::fs::write(&path, "earlier run\n")
+            .await
+            .expect("write");
+
+        let captured = capture(&CapturePolicy::File(path.clone()), 3).await;
+        let written = tokio::fs::read_to_string(&path).await.expect("read");
+        let _ = std::fs::remove_file(&path);
+
+        assert!(captured.lines.is_empty());
+        assert_eq!(captured.recent.len(), 3);
+        assert_eq!(
+            written.lines().collect::<Vec<_>>(),
+            [
+                "earlier run",
+                "[h264 @ 0x55c1e0d3a140] error while decoding MB 0 20",
+                "[h264 @ 0x55c1e0d3a140] error while decoding MB 1 20",
+                "[h264 @ 0x55c1e0d3a140] error while decoding MB 2 20",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn file_that_cant_be_opened_fails() {
+        let path = std::env::temp_dir()
+            .join("libffmpeg-missing-dir")
+            .join("capture.log");
+        let error = Capture::new(&CapturePolicy::File(path)).await.unwrap_err();
+        assert!(matches!(error, FfmpegError::CaptureFile { .. }));
+    }
+}
@@ -0,0 +1,344 @@
+use serde::{Deserialize, Serialize};
+use valuable::Valuable;
+
+use super::FfmpegError;
+use crate::duration::classify::{STDERR_TAIL_LINES, stderr_tail};
+
+/// Broad cause of an ffmpeg failure, derived from its error output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Valuable)]
+pub enum FfmpegFailureKind {
+    /// The requested encoder isn't built into this ffmpeg
+    UnknownEncoder,
+    /// An encoder (or command line) option was rejected, or the encoder couldn't be opened with them
+    EncoderOptionInvalid,
+    NoSuchFile,
+    PermissionDenied,
+    InvalidData,
+    DiskFull,
+    FilterGraphParse,
+    /// A `-map` or stream specifier matched nothing
+    StreamNotFound,
+    /// The output exists and ffmpeg wasn't allowed to overwrite it (`-n`, or no `-y` without a tty)
+    OutputExists,
+    ProtocolNotFound,
+    /// A network input or output couldn't be reached, or dropped
+    Network,
+    /// ffmpeg's catch-all, printed when nothing more specific was recognised
+    ConversionFailed,
+}
+
+impl FfmpegFailureKind {
+    /// Whether running the same command again could succeed without changing it
+    #[must_use]
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::DiskFull | Self::Network)
+    }
+}
+
+/// A classified ffmpeg failure, with the stderr lines that gave it away
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+pub struct FfmpegFailure {
+    pub kind: FfmpegFailureKind,
+    pub lines: Vec<String>,
+}
+
+// Checked in order, first match wins. "Conversion failed!" follows most other errors, so it has to
+// come last
+const PATTERNS: &[(&str, FfmpegFailureKind)] = &[
+    ("No space left on device", FfmpegFailureKind::DiskFull),
+    ("Disk quota exceeded", FfmpegFailureKind::DiskFull),
+    ("already exists. Exiting", FfmpegFailureKind::OutputExists),
+    ("Not overwriting - exiting", FfmpegFailureKind::OutputExists),
+    ("Permission denied", FfmpegFailureKind::PermissionDenied),
+    (
+        "Operation not permitted",
+        FfmpegFailureKind::PermissionDenied,
+    ),
+    ("No such file or directory", FfmpegFailureKind::NoSuchFile),
+    ("Protocol not found", FfmpegFailureKind::ProtocolNotFound),
+    ("Connection refused", FfmpegFailureKind::Network),
+    ("Connection timed out", FfmpegFailureKind::Network),
+    ("Connection reset by peer", FfmpegFailureKind::Network),
+    ("Network is unreachable", FfmpegFailureKind::Network),
+    ("Operation timed out", FfmpegFailureKind::Network),
+    ("Server returned 5", FfmpegFailureKind::Network),
+    ("Unknown encoder", FfmpegFailureKind::UnknownEncoder),
+    ("Encoder not found", FfmpegFailureKind::UnknownEncoder),
+    (
+        "Error setting option",
+        FfmpegFailureKind::EncoderOptionInvalid,
+    ),
+    (
+        "Unrecognized option",
+        FfmpegFailureKind::EncoderOptionInvalid,
+    ),
+    ("Option not found", FfmpegFailureKind::EncoderOptionInvalid),
+    (
+        "Error while opening encoder",
+        FfmpegFailureKind::EncoderOptionInvalid,
+    ),
+    (
+        "Error initializing output stream",
+        FfmpegFailureKind::EncoderOptionInvalid,
+    ),
+    ("No such filter", FfmpegFailureKind::FilterGraphParse),
+    (
+        "Error parsing filterchain",
+        FfmpegFailureKind::FilterGraphParse,
+    ),
+    (
+        "Error parsing a filter description",
+        FfmpegFailureKind::FilterGraphParse,
+    ),
+    (
+        "Error initializing complex filters",
+        FfmpegFailureKind::FilterGraphParse,
+    ),
+    ("matches no streams", FfmpegFailureKind::StreamNotFound),
+    (
+        "Invalid stream specifier",
+        FfmpegFailureKind::StreamNotFound,
+    ),
+    (
+        "does not contain any stream",
+        FfmpegFailureKind::StreamNotFound,
+    ),
+    ("Invalid data found", FfmpegFailureKind::InvalidData),
+    ("moov atom not found", FfmpegFailureKind::InvalidData),
+    ("Conversion failed!", FfmpegFailureKind::ConversionFailed),
+];
+
+/// Classify ffmpeg's stderr, `None` if nothing recognisable was printed
+#[must_use]
+pub fn classify_ffmpeg_failure(stderr_lines: &[String]) -> Option<FfmpegFailure> {
+    let mut classifier = FailureClassifier::default();
+    for line in stderr_lines {
+        classifier.push(line);
+    }
+    classifier.finish()
+}
+
+/// [`classify_ffmpeg_failure`] a line at a time, so stderr doesn't need to be kept around. Keeps the
+/// last [`STDERR_TAIL_LINES`] matching lines per kind, the ones closest to the failure
+#[derive(Debug, Default)]
+pub(crate) struct FailureClassifier {
+    /// Index into [`PATTERNS`] of the highest priority match so far
+    best: Option<usize>,
+    lines: Vec<(FfmpegFailureKind, String)>,
+}
+
+impl FailureClassifier {
+    pub(crate) fn push(&mut self, line: &str) {
+        // A line can match more than one kind, keep it for each
+        let mut matched: Vec<FfmpegFailureKind> = Vec::new();
+        for (index, (pattern, kind)) in PATTERNS.iter().enumerate() {
+            if !line.contains(pattern) || matched.contains(kind) {
+                continue;
+            }
+            if matched.is_empty() {
+                self.best = Some(self.best.map_or(index, |best| best.min(index)));
+            }
+            matched.push(*kind);
+        }
+
+        for kind in matched {
+            if self.lines.iter().filter(|(seen, _)| *seen == kind).count() == STDERR_TAIL_LINES
+                && let Some(oldest) = self.lines.iter().position(|(seen, _)| *seen == kind)
+            {
+                self.lines.remove(oldest);
+            }
+            self.lines.push((kind, line.to_string()));
+        }
+    }
+
+    pub(crate) fn finish(self) -> Option<FfmpegFailure> {
+        let kind = PATTERNS[self.best?].1;
+        let lines = self
+            .lines
+            .into_iter()
+            .filter_map(|(seen, line)| (seen == kind).then_some(line))
+            .collect();
+        Some(FfmpegFailure { kind, lines })
+    }
+}
+
+/// Build the [`FfmpegError`] for an ffmpeg that exited unsuccessfully by itself
+pub(crate) fn failure_error(code: Option<i32>, stderr_lines: &[String]) -> FfmpegError {
+    FfmpegError::ExitedUnsuccessfully {
+        code,
+        failure: classify_ffmpeg_failure(stderr_lines),
+        stderr_tail: stderr_tail(stderr_lines),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(stderr: &str) -> Vec<String> {
+        stderr.lines().map(ToString::to_string).collect()
+    }
+
+    fn kind(stderr: &str) -> Option<FfmpegFailureKind> {
+        classify_ffmpeg_failure(&lines(stderr)).map(|failure| failure.kind)
+    }
+
+    #[test]
+    fn classifies_unknown_encoder_before_conversion_failed() {
+        let failure = classify_ffmpeg_failure(&lines(
+            "[out#0/mp4 @ 0x5607a3e5c1c0] Unknown encoder 'libfdk_aac'\n\
+             [aost#0:1 @ 0x5607a3e5d2c0] Error selecting an encoder\n\
+             Error opening output file out.mp4.\n\
+             Error opening output files: Encoder not found\n\
+             Conversion failed!",
+        ));
+        assert_eq!(
+            failure,
+            Some(FfmpegFailure {
+                kind: FfmpegFailureKind::UnknownEncoder,
+                lines: lines(
+                    "[out#0/mp4 @ 0x5607a3e5c1c0] Unknown encoder 'libfdk_aac'\n\
+                     Error opening output files: Encoder not found"
+                ),
+            })
+        );
+    }
+
+    #[test]
+    fn classifies_rejected_encoder_options() {
+        assert_eq!(
+            kind(
+                "[libx264 @ 0x55f1c8a4e940] Error setting option crf to value fast.\n\
+                 [vost#0:0/libx264 @ 0x55f1c8a4d280] Error applying encoder options: Invalid argument\n\
+                 Conversion failed!"
+            ),
+            Some(FfmpegFailureKind::EncoderOptionInvalid)
+        );
+        assert_eq!(
+            kind(
+                "Unrecognized option 'preset-fast'.\nError splitting the argument list: Option not found"
+            ),
+            Some(FfmpegFailureKind::EncoderOptionInvalid)
+        );
+    }
+
+    #[test]
+    fn classifies_inputs_outputs_and_filters() {
+        for (stderr, expected) in [
+            (
+                "[in#0 @ 0x55d0c6f0a2c0] Error opening input: No such file or directory\n\
+                 Error opening input file missing.mp4.",
+                FfmpegFailureKind::NoSuchFile,
+            ),
+            (
+                "[out#0/mp4 @ 0x5581a1b1e640] Error opening output /srv/out.mp4: Permission denied",
+                FfmpegFailureKind::PermissionDenied,
+            ),
+            (
+                "[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55d0c6f0a2c0] moov atom not found\n\
+                 [in#0 @ 0x55d0c6f0a1c0] Error opening input: Invalid data found when processing input",
+                FfmpegFailureKind::InvalidData,
+            ),
+            (
+                "File 'out.mp4' already exists. Exiting.",
+                FfmpegFailureKind::OutputExists,
+            ),
+            (
+                "[AVFilterGraph @ 0x55a4b3c1d2c0] No such filter: 'scael'\n\
+                 Error parsing filterchain 'scael=1280:-2' around: \n\
+                 Conversion failed!",
+                FfmpegFailureKind::FilterGraphParse,
+            ),
+            (
+                "Stream map '0:s:0' matches no streams.\n\
+                 To ignore this, add a trailing '?' to the map.",
+                FfmpegFailureKind::StreamNotFound,
+            ),
+            (
+                "[in#0 @ 0x55d0c6f0a2c0] Error opening input: Protocol not found\n\
+                 Error opening input file srtp://127.0.0.1:9000.",
+                FfmpegFailureKind::ProtocolNotFound,
+            ),
+        ] {
+            assert_eq!(kind(stderr), Some(expected), "{stderr}");
+        }
+    }
+
+    #[test]
+    fn disk_full_wins_over_later_errors_and_is_transient() {
+        let failure = classify_ffmpeg_failure(&lines(
+            "[mp4 @ 0x55e4b1a2c3c0] Error writing trailer: No space left on device\n\
+             [out#0/mp4 @ 0x55e4b1a2b2c0] Error writing trailer of out.mp4: No space left on device\n\
+             Conversion failed!",
+        ))
+        .expect("classified");
+        assert_eq!(failure.kind, FfmpegFailureKind::DiskFull);
+        assert_eq!(failure.lines.len(), 2);
+        assert!(failure.kind.is_transient());
+        assert!(!FfmpegFailureKind::ConversionFailed.is_transient());
+    }
+
+    #[test]
+    fn classifies_network_failures() {
+        let failure = classify_ffmpeg_failure(&lines(
+            "[tcp @ 0x7f3e9c004a40] Connection to tcp://10.0.0.12:554?timeout=0 failed: Connection refused\n\
+             [in#0 @ 0x7f3e9c003c40] Error opening input: Connection refused\n\
+             Error opening input file rtsp://10.0.0.12/stream.",
+        ))
+        .expect("classified");
+        assert_eq!(failure.kind, FfmpegFailureKind::Network);
+        assert_eq!(failure.lines.len(), 2);
+    }
+
+    #[test]
+    fn falls_back_to_conversion_failed() {
+        assert_eq!(
+            kind("[vist#0:0/h264 @ 0x55c1] Decoding error\nConversion failed!"),
+            Some(FfmpegFailureKind::ConversionFailed)
+        );
+        assert_eq!(kind("Press [q] to stop, [?] for help"), None);
+    }
+
+    #[test]
+    fn classifier_matches_across_pushes() {
+        let mut classifier = FailureClassifier::default();
+        classifier.push("Conversion failed!");
+        classifier.push("[libx264 @ 0x55f1c8a4e940] Error setting option crf to value fast.");
+        let failure = classifier.finish().expect("classified");
+        assert_eq!(failure.kind, FfmpegFailureKind::EncoderOptionInvalid);
+        assert_eq!(
+            failure.lines,
+            ["[libx264 @ 0x55f1c8a4e940] Error setting option crf to value fast."]
+        );
+        assert_eq!(FailureClassifier::default().finish(), None);
+    }
+
+    #[test]
+    fn classifier_keeps_the_last_matching_lines() {
+        let mut classifier = FailureClassifier::default();
+        for frame in 0..STDERR_TAIL_LINES + 5 {
+            classifier.push(&format!(
+                "[h264 @ 0x55c1e0d3a140] Invalid data found when processing input (frame {frame})"
+            ));
+        }
+        classifier.push("Conversion failed!");
+        let failure = classifier.finish().expect("classified");
+        assert_eq!(failure.kind, FfmpegFailureKind::InvalidData);
+        assert_eq!(failure.lines.len(), STDERR_TAIL_LINES);
+        assert!(failure.lines[0].ends_with("(frame 5)"));
+        assert!(failure.lines[STDERR_TAIL_LINES - 1].ends_with("(frame 24)"));
+    }
+
+    #[test]
+    fn failure_error_attaches_the_classification() {
+        let stderr = lines("File 'out.mp4' already exists. Exiting.");
+        let error = failure_error(Some(1), &stderr);
+        assert!(matches!(
+            error,
+            FfmpegError::ExitedUnsuccessfully { code: Some(1), .. }
+        ));
+        assert_eq!(error.failure_kind(), Some(FfmpegFailureKind::OutputExists));
+        assert_eq!(error.stderr_tail(), stderr.as_slice());
+        assert!(!error.is_transient());
+    }
+}
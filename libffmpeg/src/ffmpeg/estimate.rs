@@ -0,0 +1,287 @@
+use std::{
+    collections::VecDeque,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+use super::{FfmpegProgress, ProgressState};
+use crate::duration::{DurationError, ProbeOptions, get_duration_with_options};
+
+/// How far back [`ProgressEstimator`] looks when working out the processing rate
+pub const DEFAULT_ESTIMATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Input trimming applied with `-ss`/`-t`/`-to` on the input, used to work out how long the output
+/// will be
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trim {
+    /// `-ss`
+    pub start: Option<Duration>,
+    /// `-t`, takes precedence over `end` like it does in ffmpeg
+    pub duration: Option<Duration>,
+    /// `-to`
+    pub end: Option<Duration>,
+}
+
+impl Trim {
+    /// Expected output duration for an input of `input_duration`
+    #[must_use]
+    pub fn apply(&self, input_duration: Duration) -> Duration {
+        let start = self.start.unwrap_or_default().min(input_duration);
+        let remaining = input_duration.saturating_sub(start);
+        match (self.duration, self.end) {
+            (Some(duration), _) => duration.min(remaining),
+            (None, Some(end)) => end.min(input_duration).saturating_sub(start),
+            (None, None) => remaining,
+        }
+    }
+
+    /// Probe `input` with [`get_duration_with_options`] and apply the trim, the `expected` duration
+    /// for [`super::ffmpeg_with_estimate`] and [`ProgressEstimator::new`]
+    pub async fn expected_duration<P: AsRef<Path>>(
+        &self,
+        input: P,
+        options: &ProbeOptions,
+        cancellation_token: CancellationToken,
+    ) -> Result<Duration, DurationError> {
+        let input_duration = get_duration_with_options(input, options, cancellation_token).await?;
+        Ok(self.apply(input_duration))
+    }
+
+    /// Add the trim as `-ss`/`-t`/`-to` input options, so the command and the estimate can't
+    /// disagree. Call it before the `-i` it applies to
+    pub fn input_args(&self, cmd: &mut Command) {
+        let seconds = |duration: Duration| format!("{:.6}", duration.as_secs_f64());
+        if let Some(start) = self.start {
+            cmd.arg("-ss").arg(seconds(start));
+        }
+        if let Some(duration) = self.duration {
+            cmd.arg("-t").arg(seconds(duration));
+        }
+        if let Some(end) = self.end {
+            cmd.arg("-to").arg(seconds(end));
+        }
+    }
+}
+
+/// An [`FfmpegProgress`] with completion and ETA worked out against the expected output duration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProgressEstimate {
+    pub progress: FfmpegProgress,
+    /// 0.0-1.0, `None` without an expected duration or an `out_time`
+    pub fraction: Option<f64>,
+    /// Wall clock time since the estimator was created
+    pub elapsed: Duration,
+    /// `None` until there's a processing rate to go on
+    pub eta: Option<Duration>,
+}
+
+/// Turns a sequence of [`FfmpegProgress`] into [`ProgressEstimate`]s, smoothing the processing rate
+/// over a sliding window
+#[derive(Debug, Clone)]
+pub struct ProgressEstimator {
+    expected: Option<Duration>,
+    window: Duration,
+    started: Instant,
+    samples: VecDeque<(Instant, Duration)>,
+}
+
+impl ProgressEstimator {
+    /// `expected` is the output duration, usually [`Trim::apply`] to the input's
+    /// [`crate::duration::get_duration`]
+    #[must_use]
+    pub fn new(expected: Option<Duration>) -> Self {
+        Self::with_window(expected, DEFAULT_ESTIMATE_WINDOW)
+    }
+
+    #[must_use]
+    pub fn with_window(expected: Option<Duration>, window: Duration) -> Self {
+        Self {
+            expected,
+            window,
+            started: Instant::now(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn update(&mut self, progress: FfmpegProgress) -> ProgressEstimate {
+        self.update_at(progress, Instant::now())
+    }
+
+    pub(crate) fn update_at(&mut self, progress: FfmpegProgress, now: Instant) -> ProgressEstimate {
+        let elapsed = now.saturating_duration_since(self.started);
+
+        if progress.state == ProgressState::End {
+            return ProgressEstimate {
+                fraction: self.expected.map(|_| 1.0),
+                elapsed,
+                eta: Some(Duration::ZERO),
+                progress,
+            };
+        }
+
+        let Some(out_time) = progress.out_time else {
+            return ProgressEstimate {
+                fraction: None,
+                elapsed,
+                eta: None,
+                progress,
+            };
+        };
+
+        self.samples.push_back((now, out_time));
+        while self.samples.len() > 2
+            && self
+                .samples
+                .front()
+                .is_some_and(|(at, _)| now.saturating_duration_since(*at) > self.window)
+        {
+            self.samples.pop_front();
+        }
+
+        let fraction = self.expected.map(|expected| {
+            if expected.is_zero() {
+                1.0
+            } else {
+                (out_time.as_secs_f64() / expected.as_secs_f64()).clamp(0.0, 1.0)
+            }
+        });
+
+        // Output seconds per wall clock second, falling back to ffmpeg's own speed until the window
+        // has something to go on
+        let rate = match (self.samples.front(), self.samples.back()) {
+            (Some((first_at, first_out)), Some((last_at, last_out))) if last_at > first_at => Some(
+                last_out.saturating_sub(*first_out).as_secs_f64()
+                    / last_at.saturating_duration_since(*first_at).as_secs_f64(),
+            ),
+            _ => progress.speed,
+        }
+        .filter(|rate| *rate > f64::EPSILON);
+
+        let eta = self.expected.zip(rate).and_then(|(expected, rate)| {
+            Duration::try_from_secs_f64(expected.saturating_sub(out_time).as_secs_f64() / rate).ok()
+        });
+
+        ProgressEstimate {
+            progress,
+            fraction,
+            elapsed,
+            eta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: Duration = Duration::from_mins(2);
+
+    fn at(out_time_secs: u64) -> FfmpegProgress {
+        FfmpegProgress {
+            out_time: Some(Duration::from_secs(out_time_secs)),
+            ..FfmpegProgress::default()
+        }
+    }
+
+    #[test]
+    fn trim_applies_like_ffmpeg() {
+        let secs = |secs| Some(Duration::from_secs(secs));
+        let trim = |start, duration, end| Trim {
+            start,
+            duration,
+            end,
+        };
+        assert_eq!(trim(None, None, None).apply(INPUT), INPUT);
+        assert_eq!(
+            trim(secs(30), None, None).apply(INPUT),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            trim(secs(30), None, secs(60)).apply(INPUT),
+            Duration::from_secs(30)
+        );
+        // -t wins over -to
+        assert_eq!(
+            trim(secs(30), secs(10), secs(60)).apply(INPUT),
+            Duration::from_secs(10)
+        );
+        // Past the end of the input
+        assert_eq!(
+            trim(secs(100), secs(60), None).apply(INPUT),
+            Duration::from_secs(20)
+        );
+        assert_eq!(trim(secs(200), None, None).apply(INPUT), Duration::ZERO);
+        assert_eq!(trim(secs(30), None, secs(10)).apply(INPUT), Duration::ZERO);
+    }
+
+    #[test]
+    fn trim_input_args() {
+        let trim = Trim {
+            start: Some(Duration::from_secs_f64(30.5)),
+            duration: Some(Duration::from_secs(10)),
+            end: None,
+        };
+        let mut cmd = Command::new("ffmpeg");
+        trim.input_args(&mut cmd);
+        assert_eq!(
+            cmd.as_std().get_args().collect::<Vec<_>>(),
+            ["-ss", "30.500000", "-t", "10.000000"]
+        );
+    }
+
+    #[test]
+    fn estimates_fraction_and_eta_from_the_window() {
+        let start = Instant::now();
+        let mut estimator = ProgressEstimator::with_window(Some(INPUT), Duration::from_secs(10));
+        estimator.started = start;
+
+        let first = estimator.update_at(at(0), start);
+        assert_eq!((first.fraction, first.eta), (Some(0.0), None));
+
+        // 2 seconds of output per second of wall clock, 100 seconds of output left
+        let estimate = estimator.update_at(at(20), start + Duration::from_secs(10));
+        assert_eq!(
+            (estimate.fraction, estimate.eta, estimate.elapsed),
+            (
+                Some(20.0 / 120.0),
+                Some(Duration::from_secs(50)),
+                Duration::from_secs(10)
+            )
+        );
+    }
+
+    #[test]
+    fn falls_back_to_ffmpeg_speed_without_a_rate() {
+        let start = Instant::now();
+        let mut estimator = ProgressEstimator::new(Some(INPUT));
+        estimator.started = start;
+        let estimate = estimator.update_at(
+            FfmpegProgress {
+                speed: Some(4.0),
+                ..at(40)
+            },
+            start,
+        );
+        assert_eq!(estimate.eta, Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn end_and_missing_out_time() {
+        let mut estimator = ProgressEstimator::new(Some(INPUT));
+        let missing = estimator.update(FfmpegProgress::default());
+        assert_eq!((missing.fraction, missing.eta), (None, None));
+
+        let end = estimator.update(FfmpegProgress {
+            state: ProgressState::End,
+            ..at(119)
+        });
+        assert_eq!((end.fraction, end.eta), (Some(1.0), Some(Duration::ZERO)));
+
+        let mut unknown = ProgressEstimator::new(None);
+        assert_eq!(unknown.update(at(10)).fraction, None);
+    }
+}
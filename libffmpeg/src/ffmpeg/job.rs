@@ -0,0 +1,838 @@
+use std::{
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use derive_builder::Builder;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    process::{Child, Command},
+    sync::{Notify, broadcast, mpsc, watch},
+    task::JoinHandle,
+};
+use tokio_util::{
+    future::FutureExt,
+    sync::{CancellationToken, DropGuard},
+};
+use tracing::{Instrument, instrument};
+use valuable::Valuable;
+
+use super::{
+    FfmpegError, FfmpegProgress, ProgressState,
+    capture::{Capture, CapturePolicy, Captured},
+    classify::{FailureClassifier, FfmpegFailure},
+    progress::{ProgressParser, is_progress_line},
+    shutdown::{ShutdownAction, ShutdownPolicy, StopReason, shut_down},
+};
+use crate::{
+    log::{LogEvent, LogLevel},
+    process,
+};
+
+/// Log events buffered for [`FfmpegJob::log_stream`], older ones are dropped once it's full
+const LOG_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Builder)]
+#[builder(default)]
+pub struct FfmpegJobOptions {
+    /// Minimum time between progress updates, blocks in between are coalesced into the latest one.
+    /// `None` publishes every block ffmpeg writes (every 0.5s by default, see `-stats_period`)
+    #[builder(setter(into, strip_option))]
+    pub progress_interval: Option<Duration>,
+    /// How ffmpeg is stopped when the job is cancelled
+    pub shutdown: ShutdownPolicy,
+    /// Passed as `-loglevel level+<log_level>`, so every stderr line carries its level. A `-loglevel`
+    /// added in `prepare` replaces it and the lines lose their level
+    pub log_level: LogLevel,
+    /// Which stderr lines end up in [`FfmpegExit::stderr_lines`]
+    pub stderr_capture: CapturePolicy,
+    /// Longest the job may run before it's shut down. Like [`Self::stall_timeout`], enforced by every
+    /// runner taking these options, [`super::ffmpeg_graceful`] and [`super::ffmpeg_graceful_raw`]
+    /// only stop on cancellation
+    #[builder(setter(into, strip_option))]
+    pub timeout: Option<Duration>,
+    /// Longest the job may go without progress (`out_time`, `frame` or `total_size` moving on)
+    /// before it's shut down, counted from the start of the job. ffmpeg keeps writing progress
+    /// blocks while it waits on a stalled input, so this catches hung network inputs
+    #[builder(setter(into, strip_option))]
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for FfmpegJobOptions {
+    fn default() -> Self {
+        Self {
+            progress_interval: None,
+            shutdown: ShutdownPolicy::default(),
+            log_level: LogLevel::Error,
+            stderr_capture: CapturePolicy::All,
+            timeout: None,
+            stall_timeout: None,
+        }
+    }
+}
+
+impl FfmpegJobOptionsBuilder {
+    /// Throttle progress to at most `updates` per second
+    pub fn max_updates_per_second(&mut self, updates: u32) -> &mut Self {
+        self.progress_interval(Duration::from_secs(1) / updates.max(1))
+    }
+}
+
+/// How an ffmpeg job's process ended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+pub struct FfmpegExit {
+    pub code: Option<i32>,
+    pub success: bool,
+    /// The signal that terminated the process, unix only
+    pub signal: Option<i32>,
+    /// stdout, for runs that keep it (see [`super::ffmpeg_raw`]). Empty for jobs, their stdout is
+    /// progress
+    pub stdout_lines: Vec<String>,
+    /// stderr, as kept by [`FfmpegJobOptions::stderr_capture`]. With
+    /// [`CapturePolicy::HeadTail`] the dropped lines were between the head and the tail
+    pub stderr_lines: Vec<String>,
+    /// Number of stderr lines left out of `stderr_lines`
+    pub stderr_dropped: usize,
+    /// The last non-empty lines of stderr, whatever the capture policy
+    pub stderr_tail: Vec<String>,
+    /// The cause recognised in stderr when ffmpeg didn't succeed, see
+    /// [`super::classify_ffmpeg_failure`]
+    pub failure: Option<FfmpegFailure>,
+    /// Why ffmpeg was shut down, `None` if it exited by itself
+    pub stop_reason: Option<StopReason>,
+    /// The shutdown step ffmpeg exited after, `None` if it exited by itself
+    pub stopped_by: Option<ShutdownAction>,
+}
+
+impl FfmpegExit {
+    fn new(
+        status: ExitStatus,
+        stdout_lines: Vec<String>,
+        stderr: Captured,
+        failure: Option<FfmpegFailure>,
+        stopped: Option<(StopReason, ShutdownAction)>,
+    ) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+
+        Self {
+            code: status.code(),
+            success: status.success(),
+            signal,
+            stdout_lines,
+            stderr_lines: stderr.lines,
+            stderr_dropped: stderr.dropped,
+            stderr_tail: stderr.recent,
+            failure: failure.filter(|_| !status.success()),
+            stop_reason: stopped.map(|(reason, _)| reason),
+            stopped_by: stopped.map(|(_, action)| action),
+        }
+    }
+
+    /// Turn a stopped, killed or unsuccessful exit of a run with `options` into an [`FfmpegError`]
+    pub(crate) fn check_with(self, options: &FfmpegJobOptions) -> Result<Self, FfmpegError> {
+        self.check(&Limits::of(options))
+    }
+
+    /// Turn a stopped, killed or unsuccessful exit into an [`FfmpegError`]
+    fn check(self, limits: &Limits) -> Result<Self, FfmpegError> {
+        let duration_ms =
+            |duration: Option<Duration>| duration.map_or(0, |duration| duration.as_millis() as u64);
+        match self.stop_reason {
+            Some(StopReason::Cancelled) => {
+                tracing::debug!(stopped_by = ?self.stopped_by, "ffmpeg job cancelled");
+                return Err(FfmpegError::Cancelled {
+                    stopped_by: self.stopped_by,
+                    stderr_tail: self.stderr_tail,
+                });
+            }
+            Some(StopReason::TimedOut) => {
+                return Err(FfmpegError::TimedOut {
+                    timeout_ms: duration_ms(limits.timeout),
+                    stderr_tail: self.stderr_tail,
+                });
+            }
+            Some(StopReason::Stalled) => {
+                return Err(FfmpegError::Stalled {
+                    stall_timeout_ms: duration_ms(limits.stall_timeout),
+                    stderr_tail: self.stderr_tail,
+                });
+            }
+            None => {}
+        }
+        if self.success {
+            return Ok(self);
+        }
+
+        let (code, signal) = (self.code, self.signal);
+        let error = match self.signal {
+            Some(signal) => FfmpegError::KilledBySignal {
+                signal: Some(signal),
+                stderr_tail: self.stderr_tail,
+            },
+            None => FfmpegError::ExitedUnsuccessfully {
+                code: self.code,
+                failure: self.failure,
+                stderr_tail: self.stderr_tail,
+            },
+        };
+        tracing::error!(
+            code = ?code,
+            signal = ?signal,
+            failure_kind = ?error.failure_kind(),
+            stderr_tail = ?error.stderr_tail(),
+            "ffmpeg exited unsuccessfully"
+        );
+        Err(error)
+    }
+}
+
+/// A running ffmpeg process.
+///
+/// Progress is published to a [`watch`] channel, so reading it never holds up ffmpeg: slow
+/// consumers just see the latest block.
+///
+/// Cancelling the job, or dropping it (or the future returned by [`FfmpegJob::wait`]), stops ffmpeg
+/// following [`FfmpegJobOptions::shutdown`]. By default that sends `q` to ffmpeg's stdin so it can
+/// finalize its outputs (e.g. write the moov atom of an MP4), killing it if it hasn't exited within
+/// 5 seconds. Don't pass `-nostdin`, ffmpeg can't be asked to quit without it.
+///
+/// The same shutdown is used when the job runs past [`FfmpegJobOptions::timeout`] or stops making
+/// progress for [`FfmpegJobOptions::stall_timeout`].
+#[derive(Debug)]
+pub struct FfmpegJob {
+    pid: Option<u32>,
+    limits: Limits,
+    progress_rx: watch::Receiver<Option<FfmpegProgress>>,
+    log_rx: broadcast::Receiver<LogEvent>,
+    cancellation_token: CancellationToken,
+    handle: JoinHandle<Result<FfmpegExit, FfmpegError>>,
+    _guard: DropGuard,
+}
+
+impl FfmpegJob {
+    /// Start ffmpeg, the job runs in the background until it exits or is cancelled.
+    ///
+    /// NOTE: This adds `-hide_banner -progress pipe:1 -loglevel level+<log_level>` to the BEGINNING of the `prepare`d command
+    #[instrument(skip(prepare, cancellation_token))]
+    pub async fn spawn<Prepare>(
+        options: &FfmpegJobOptions,
+        cancellation_token: CancellationToken,
+        prepare: Prepare,
+    ) -> Result<Self, FfmpegError>
+    where
+        Prepare: FnOnce(&mut Command),
+    {
+        Self::spawn_with(
+            options,
+            JobStdout::Progress,
+            None,
+            cancellation_token,
+            prepare,
+        )
+        .await
+    }
+
+    /// [`FfmpegJob::spawn`], with stdout handled as `stdout` says and every stderr line that isn't
+    /// progress also forwarded to `stderr_tx`. Forwarded lines are sent as they're read, so a slow
+    /// receiver holds up ffmpeg until the job is cancelled
+    pub(crate) async fn spawn_with<Prepare>(
+        options: &FfmpegJobOptions,
+        stdout: JobStdout,
+        stderr_tx: Option<mpsc::Sender<String>>,
+        cancellation_token: CancellationToken,
+        prepare: Prepare,
+    ) -> Result<Self, FfmpegError>
+    where
+        Prepare: FnOnce(&mut Command),
+    {
+        tracing::debug!("Starting ffmpeg job");
+
+        let capture = Capture::new(&options.stderr_capture).await?;
+
+        // stdin is piped so ffmpeg can be asked to quit
+        let (child, ffmpeg_path) = process::spawn("ffmpeg", Stdio::piped(), |cmd| {
+            cmd.arg("-hide_banner");
+            cmd.arg("-progress").arg(match stdout {
+                JobStdout::Progress => "pipe:1",
+                JobStdout::Keep | JobStdout::Forward(_) | JobStdout::Chunks { .. } => "pipe:2",
+            });
+            cmd.arg("-loglevel")
+                .arg(format!("level+{}", options.log_level));
+            prepare(cmd);
+        })
+        .await?;
+        let pid = child.id();
+
+        tracing::info!(
+            ffmpeg_path = %ffmpeg_path.display(),
+            pid = ?pid,
+            "Executing ffmpeg job"
+        );
+
+        let (progress_tx, progress_rx) = watch::channel(None);
+        let (log_tx, log_rx) = broadcast::channel(LOG_CHANNEL_CAPACITY);
+        let cancellation_token = cancellation_token.child_token();
+        let handle = tokio::spawn(
+            drive(
+                child,
+                Outputs {
+                    stdout,
+                    progress_tx,
+                    log_tx,
+                    stderr_tx,
+                    capture,
+                },
+                options.clone(),
+                cancellation_token.clone(),
+            )
+            .in_current_span(),
+        );
+
+        Ok(Self {
+            pid,
+            limits: Limits::of(options),
+            progress_rx,
+            log_rx,
+            _guard: cancellation_token.clone().drop_guard(),
+            cancellation_token,
+            handle,
+        })
+    }
+
+    /// OS process id, `None` if it had already exited when spawned
+    #[must_use]
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// The latest progress block, `None` before the first one
+    #[must_use]
+    pub fn latest_progress(&self) -> Option<FfmpegProgress> {
+        self.progress_rx.borrow().clone()
+    }
+
+    /// A receiver for the latest progress block, closed once the job finishes
+    #[must_use]
+    pub fn progress(&self) -> watch::Receiver<Option<FfmpegProgress>> {
+        self.progress_rx.clone()
+    }
+
+    /// Progress blocks as they're published, ending when the job finishes. Blocks published while
+    /// the consumer is busy are coalesced into the latest
+    pub fn progress_stream(&self) -> impl Stream<Item = FfmpegProgress> + Send + 'static {
+        futures::stream::unfold(self.progress_rx.clone(), |mut rx| async move {
+            loop {
+                rx.changed().await.ok()?;
+                let progress = rx.borrow_and_update().clone();
+                if let Some(progress) = progress {
+                    return Some((progress, rx));
+                }
+            }
+        })
+    }
+
+    /// ffmpeg's stderr as [`LogEvent`]s, ending when the job finishes. Every event is also emitted to
+    /// tracing (see [`LogEvent::emit`]).
+    ///
+    /// The first stream starts from the beginning of the job, later ones from when they're created.
+    /// Events are dropped rather than holding up ffmpeg when a consumer falls too far behind
+    pub fn log_stream(&mut self) -> impl Stream<Item = LogEvent> + Send + 'static {
+        let later = self.log_rx.resubscribe();
+        let rx = std::mem::replace(&mut self.log_rx, later);
+        futures::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "Log stream fell behind, dropped events");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Ask ffmpeg to stop, [`FfmpegJob::wait_raw`] still returns how it exited
+    pub fn cancel(&self) {
+        self.cancellation_token.cancel();
+    }
+
+    /// Wait for ffmpeg to exit, failing with [`FfmpegError::Cancelled`], [`FfmpegError::TimedOut`],
+    /// [`FfmpegError::Stalled`], [`FfmpegError::KilledBySignal`] or
+    /// [`FfmpegError::ExitedUnsuccessfully`] unless it exits successfully
+    pub async fn wait(self) -> Result<FfmpegExit, FfmpegError> {
+        let limits = self.limits;
+        self.wait_raw().await?.check(&limits)
+    }
+
+    /// [`FfmpegJob::wait`], returning the exit as is whether or not ffmpeg succeeded
+    pub async fn wait_raw(self) -> Result<FfmpegExit, FfmpegError> {
+        let Self { handle, _guard, .. } = self;
+        handle
+            .await
+            .map_err(|e| FfmpegError::Join {
+                inner_error: e.into(),
+            })
+            .inspect_err(|e| tracing::error!(error = %e, "ffmpeg job task failed"))?
+    }
+}
+
+/// The job's run limits, kept to report them in errors
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+}
+
+impl Limits {
+    fn of(options: &FfmpegJobOptions) -> Self {
+        Self {
+            timeout: options.timeout,
+            stall_timeout: options.stall_timeout,
+        }
+    }
+}
+
+/// What a job does with ffmpeg's stdout. Unless it's [`JobStdout::Progress`], `-progress` goes to
+/// stderr and its lines are told apart from log lines by [`is_progress_line`]
+#[derive(Debug)]
+pub(crate) enum JobStdout {
+    /// `-progress pipe:1`
+    Progress,
+    /// Kept in [`FfmpegExit::stdout_lines`], e.g. `-filters` or a `stats_file=-`
+    Keep,
+    /// Sent to the channel line by line, e.g. a `metadata=print:file='pipe\:1'` sink
+    Forward(mpsc::Sender<String>),
+    /// Sent to the channel in `size` byte chunks, e.g. `rawvideo` frames. A trailing partial chunk
+    /// is dropped
+    Chunks {
+        size: usize,
+        tx: mpsc::Sender<Vec<u8>>,
+    },
+}
+
+/// Where [`drive`] sends what ffmpeg writes
+struct Outputs {
+    stdout: JobStdout,
+    progress_tx: watch::Sender<Option<FfmpegProgress>>,
+    log_tx: broadcast::Sender<LogEvent>,
+    stderr_tx: Option<mpsc::Sender<String>>,
+    capture: Capture,
+}
+
+/// Publishes progress, holding back blocks that arrive within `interval` of the last published one
+struct Throttle {
+    interval: Option<Duration>,
+    last_published: Option<Instant>,
+    pending: Option<FfmpegProgress>,
+}
+
+impl Throttle {
+    fn push(&mut self, progress: FfmpegProgress, tx: &watch::Sender<Option<FfmpegProgress>>) {
+        let now = Instant::now();
+        let due = match (self.interval, self.last_published) {
+            (Some(interval), Some(last)) => now.saturating_duration_since(last) >= interval,
+            _ => true,
+        };
+        if due || progress.state == ProgressState::End {
+            self.pending = None;
+            self.last_published = Some(now);
+            tx.send_replace(Some(progress));
+        } else {
+            self.pending = Some(progress);
+        }
+    }
+
+    fn flush(&mut self, tx: &watch::Sender<Option<FfmpegProgress>>) {
+        if let Some(progress) = self.pending.take() {
+            tx.send_replace(Some(progress));
+        }
+    }
+}
+
+async fn drive(
+    mut child: Child,
+    outputs: Outputs,
+    options: FfmpegJobOptions,
+    cancellation_token: CancellationToken,
+) -> Result<FfmpegExit, FfmpegError> {
+    let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        unreachable!("stdout and stderr are piped")
+    };
+    // Held open until ffmpeg exits, ffmpeg reads interactive commands from it
+    let mut stdin = child.stdin.take();
+    // Notified whenever ffmpeg makes progress, for the stall watchdog
+    let advanced = Notify::new();
+
+    let Outputs {
+        stdout: stdout_handling,
+        progress_tx,
+        log_tx,
+        stderr_tx,
+        capture,
+    } = outputs;
+    let progress = ProgressSink {
+        parser: ProgressParser::default(),
+        throttle: Throttle {
+            interval: options.progress_interval,
+            last_published: None,
+            pending: None,
+        },
+        position: None,
+        advanced: &advanced,
+        tx: &progress_tx,
+    };
+    // Progress is read from whichever pipe it was sent to
+    let (stdout_progress, stderr_progress) = match stdout_handling {
+        JobStdout::Progress => (Some(progress), None),
+        JobStdout::Keep | JobStdout::Forward(_) | JobStdout::Chunks { .. } => {
+            (None, Some(progress))
+        }
+    };
+
+    let wait = async {
+        let reason = tokio::select! {
+            status = child.wait() => return (status, None),
+            () = cancellation_token.cancelled() => StopReason::Cancelled,
+            () = elapsed(options.timeout) => StopReason::TimedOut,
+            () = stalled(&advanced, options.stall_timeout) => StopReason::Stalled,
+        };
+        // ffmpeg may have exited while the other branch won, don't report it as stopped
+        if let Ok(Some(status)) = child.try_wait() {
+            tracing::debug!(reason = %reason, "ffmpeg exited before it was shut down");
+            return (Ok(status), None);
+        }
+        match reason {
+            StopReason::Cancelled => tracing::debug!("ffmpeg job cancelled, shutting down"),
+            StopReason::TimedOut => {
+                tracing::warn!(timeout = ?options.timeout, "ffmpeg job timed out, shutting down");
+            }
+            StopReason::Stalled => {
+                tracing::warn!(
+                    stall_timeout = ?options.stall_timeout,
+                    "ffmpeg job stopped making progress, shutting down"
+                );
+            }
+        }
+        let (status, action) = shut_down(&mut child, &mut stdin, &options.shutdown).await;
+        (status, Some((reason, action)))
+    };
+
+    let read_stderr = read_stderr(
+        stderr,
+        StderrOutputs {
+            progress: stderr_progress,
+            log_tx,
+            stderr_tx,
+            capture,
+        },
+        &cancellation_token,
+    );
+    let ((status, stopped), stdout_lines, (stderr, failure)) = tokio::join!(
+        wait,
+        read_stdout(
+            stdout,
+            stdout_handling,
+            stdout_progress,
+            &cancellation_token
+        ),
+        read_stderr
+    );
+    let status = status
+        .map_err(|e| FfmpegError::Pipe {
+            inner_error: e.into(),
+        })
+        .inspect_err(|e| tracing::error!(error = %e, "Failed to wait for ffmpeg"))?;
+
+    let exit = FfmpegExit::new(status, stdout_lines, stderr, failure, stopped);
+    tracing::debug!(exit = exit.as_value(), "ffmpeg job completed");
+
+    Ok(exit)
+}
+
+/// Resolves once `limit` has passed, never without one
+async fn elapsed(limit: Option<Duration>) {
+    match limit {
+        Some(limit) => tokio::time::sleep(limit).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once `advanced` goes `limit` without being notified, never without one
+async fn stalled(advanced: &Notify, limit: Option<Duration>) {
+    let Some(limit) = limit else {
+        return std::future::pending().await;
+    };
+    while tokio::time::timeout(limit, advanced.notified())
+        .await
+        .is_ok()
+    {}
+}
+
+/// Parses progress lines, publishing blocks through the throttle and waking the stall watchdog
+struct ProgressSink<'a> {
+    parser: ProgressParser,
+    throttle: Throttle,
+    /// `out_time`, `frame` and `total_size` of the last block, ffmpeg has advanced when they change
+    position: Option<(Option<Duration>, Option<u64>, Option<u64>)>,
+    advanced: &'a Notify,
+    tx: &'a watch::Sender<Option<FfmpegProgress>>,
+}
+
+impl ProgressSink<'_> {
+    fn push(&mut self, line: &str) {
+        let Some(progress) = self.parser.push(line) else {
+            return;
+        };
+        let current = Some((progress.out_time, progress.frame, progress.total_size));
+        if current != self.position {
+            self.position = current;
+            self.advanced.notify_one();
+        }
+        self.throttle.push(progress, self.tx);
+    }
+
+    fn finish(mut self) {
+        self.throttle.flush(self.tx);
+    }
+}
+
+/// Read stdout, as progress, kept lines, forwarded lines or forwarded chunks. Returns the kept lines
+async fn read_stdout<R: AsyncRead + Unpin>(
+    reader: R,
+    handling: JobStdout,
+    mut progress: Option<ProgressSink<'_>>,
+    cancellation_token: &CancellationToken,
+) -> Vec<String> {
+    if let JobStdout::Chunks { size, tx } = handling {
+        read_chunks(reader, size, &tx, cancellation_token).await;
+        return Vec::new();
+    }
+
+    let mut kept = Vec::new();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => match &handling {
+                JobStdout::Progress => {
+                    if let Some(progress) = &mut progress {
+                        progress.push(&line);
+                    }
+                }
+                JobStdout::Keep => kept.push(line),
+                JobStdout::Forward(tx) => forward(tx, line, cancellation_token).await,
+                JobStdout::Chunks { .. } => unreachable!("chunks are read by read_chunks"),
+            },
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read ffmpeg stdout");
+                break;
+            }
+        }
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    kept
+}
+
+/// Read stdout in `size` byte chunks until ffmpeg closes it, sending each to `tx`
+async fn read_chunks<R: AsyncRead + Unpin>(
+    mut reader: R,
+    size: usize,
+    tx: &mpsc::Sender<Vec<u8>>,
+    cancellation_token: &CancellationToken,
+) {
+    loop {
+        let mut chunk = vec![0u8; size];
+        match reader.read_exact(&mut chunk).await {
+            Ok(_) => forward(tx, chunk, cancellation_token).await,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read ffmpeg stdout");
+                break;
+            }
+        }
+    }
+}
+
+/// Where [`read_stderr`] sends stderr lines
+struct StderrOutputs<'a> {
+    /// Set when progress was sent to stderr
+    progress: Option<ProgressSink<'a>>,
+    log_tx: broadcast::Sender<LogEvent>,
+    stderr_tx: Option<mpsc::Sender<String>>,
+    capture: Capture,
+}
+
+/// Capture and classify stderr, emitting and publishing each line as a [`LogEvent`]
+async fn read_stderr<R: AsyncRead + Unpin>(
+    reader: R,
+    outputs: StderrOutputs<'_>,
+    cancellation_token: &CancellationToken,
+) -> (Captured, Option<FfmpegFailure>) {
+    let StderrOutputs {
+        mut progress,
+        log_tx,
+        stderr_tx,
+        mut capture,
+    } = outputs;
+    let mut lines = BufReader::new(reader).lines();
+    let mut classifier = FailureClassifier::default();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                if let Some(progress) = &mut progress
+                    && is_progress_line(&line)
+                {
+                    progress.push(&line);
+                    continue;
+                }
+                let event = LogEvent::parse(&line);
+                event.emit();
+                // Only fails without receivers
+                let _ = log_tx.send(event);
+                classifier.push(&line);
+                if let Some(stderr_tx) = &stderr_tx {
+                    forward(stderr_tx, line.clone(), cancellation_token).await;
+                }
+                capture.push(line).await;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read ffmpeg output");
+                break;
+            }
+        }
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    (capture.finish().await, classifier.finish())
+}
+
+/// Send `output` to `tx`, giving up once the job is cancelled so a stalled receiver can't hold up
+/// shutdown
+async fn forward<T>(tx: &mpsc::Sender<T>, output: T, cancellation_token: &CancellationToken) {
+    match tx
+        .send(output)
+        .with_cancellation_token(cancellation_token)
+        .await
+    {
+        Some(Ok(())) => {}
+        Some(Err(e)) => tracing::trace!(error = %e, "Output receiver dropped"),
+        None => tracing::trace!("Cancelled, dropping output"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(frame: u64, state: ProgressState) -> FfmpegProgress {
+        FfmpegProgress {
+            frame: Some(frame),
+            state,
+            ..FfmpegProgress::default()
+        }
+    }
+
+    fn published(rx: &watch::Receiver<Option<FfmpegProgress>>) -> Option<u64> {
+        rx.borrow().as_ref().and_then(|progress| progress.frame)
+    }
+
+    #[test]
+    fn throttle_coalesces_blocks_within_the_interval() {
+        let (tx, rx) = watch::channel(None);
+        let mut throttle = Throttle {
+            interval: Some(Duration::from_hours(1)),
+            last_published: None,
+            pending: None,
+        };
+
+        throttle.push(block(12, ProgressState::Continue), &tx);
+        assert_eq!(published(&rx), Some(12));
+
+        throttle.push(block(24, ProgressState::Continue), &tx);
+        throttle.push(block(36, ProgressState::Continue), &tx);
+        assert_eq!(published(&rx), Some(12));
+
+        throttle.flush(&tx);
+        assert_eq!(published(&rx), Some(36));
+    }
+
+    #[test]
+    fn throttle_always_publishes_the_last_block() {
+        let (tx, rx) = watch::channel(None);
+        let mut throttle = Throttle {
+            interval: Some(Duration::from_hours(1)),
+            last_published: None,
+            pending: None,
+        };
+
+        throttle.push(block(12, ProgressState::Continue), &tx);
+        throttle.push(block(24, ProgressState::Continue), &tx);
+        throttle.push(block(30, ProgressState::End), &tx);
+        assert_eq!(published(&rx), Some(30));
+
+        // Nothing left pending to overwrite the last block
+        throttle.flush(&tx);
+        assert_eq!(published(&rx), Some(30));
+    }
+
+    #[test]
+    fn throttle_without_interval_publishes_everything() {
+        let (tx, mut rx) = watch::channel(None);
+        let mut throttle = Throttle {
+            interval: None,
+            last_published: None,
+            pending: None,
+        };
+
+        for frame in [12, 24, 36] {
+            throttle.push(block(frame, ProgressState::Continue), &tx);
+            assert!(rx.has_changed().unwrap());
+            assert_eq!(
+                rx.borrow_and_update()
+                    .as_ref()
+                    .and_then(|progress| progress.frame),
+                Some(frame)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn stall_watchdog_waits_for_progress_to_stop() {
+        let advanced = Notify::new();
+        let started = std::time::Instant::now();
+        let progress = async {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                advanced.notify_one();
+            }
+        };
+        tokio::join!(
+            stalled(&advanced, Some(Duration::from_millis(50))),
+            progress
+        );
+        // Ten blocks 10ms apart, then 50ms without one
+        assert!(started.elapsed() >= Duration::from_millis(140));
+    }
+
+    #[tokio::test]
+    async fn limits_without_a_duration_never_fire() {
+        let advanced = Notify::new();
+        let limit = Duration::from_millis(20);
+        assert!(
+            tokio::time::timeout(limit, stalled(&advanced, None))
+                .await
+                .is_err()
+        );
+        assert!(tokio::time::timeout(limit, elapsed(None)).await.is_err());
+    }
+}
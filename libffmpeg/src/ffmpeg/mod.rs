@@ -0,0 +1,549 @@
+pub mod capture;
+pub mod classify;
+pub mod estimate;
+pub mod job;
+pub mod progress;
+pub mod shutdown;
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use libcmd::{CommandError, CommandExit, CommandMonitorClient, CommandMonitorServer};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{process::Command, sync::mpsc::error::TrySendError};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, instrument};
+use valuable::Valuable;
+
+use liberror::AnyError;
+
+use crate::{
+    duration::classify::stderr_tail,
+    env::find::{FindBinaryError, find_binary_env},
+};
+
+pub use capture::CapturePolicy;
+pub use classify::{FfmpegFailure, FfmpegFailureKind, classify_ffmpeg_failure};
+pub use estimate::{DEFAULT_ESTIMATE_WINDOW, ProgressEstimate, ProgressEstimator, Trim};
+use job::JobStdout;
+pub use job::{FfmpegExit, FfmpegJob, FfmpegJobOptions, FfmpegJobOptionsBuilder};
+pub use progress::{FfmpegProgress, ProgressState, StreamQuality};
+pub use shutdown::{ShutdownAction, ShutdownPolicy, ShutdownStep, StopReason};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Valuable, Error)]
+pub enum FfmpegError {
+    #[error(transparent)]
+    Command {
+        #[from]
+        inner_error: CommandError,
+    },
+    #[error(transparent)]
+    FindBinary {
+        #[from]
+        inner_error: FindBinaryError,
+    },
+    #[error(
+        "Unable to locate ffmpeg on your PATH, set LIBFFMPEG_FFMPEG_PATH to the binary, or update your PATH"
+    )]
+    NotFound,
+    #[error("Failed to spawn ffmpeg: {inner_error}")]
+    Spawn { inner_error: AnyError },
+    #[error("Failed to communicate with ffmpeg: {inner_error}")]
+    Pipe { inner_error: AnyError },
+    #[error("ffmpeg job task failed: {inner_error}")]
+    Join { inner_error: AnyError },
+    #[error("Failed to open '{path}' to capture ffmpeg's output: {inner_error}")]
+    CaptureFile { path: String, inner_error: AnyError },
+    #[error("Process returned, but no exit status was present: stdout_lines={}, stderr_lines={}", result.stdout_lines.len(), result.stderr_lines.len())]
+    IncompleteSubprocess { result: CommandExit },
+    #[error("ffmpeg was cancelled before it finished, stopped by {}: {}", stopped_by.map_or_else(|| "unknown".to_string(), |action| action.to_string()), stderr_tail.join("\n"))]
+    Cancelled {
+        /// The shutdown step ffmpeg exited after, `None` if it's not known
+        stopped_by: Option<ShutdownAction>,
+        stderr_tail: Vec<String>,
+    },
+    #[error("ffmpeg did not finish within {timeout_ms}ms: {}", stderr_tail.join("\n"))]
+    TimedOut {
+        timeout_ms: u64,
+        stderr_tail: Vec<String>,
+    },
+    #[error("ffmpeg made no progress for {stall_timeout_ms}ms: {}", stderr_tail.join("\n"))]
+    Stalled {
+        stall_timeout_ms: u64,
+        stderr_tail: Vec<String>,
+    },
+    #[error("ffmpeg was killed by signal {}: {}", signal.map_or_else(|| "unknown".to_string(), |s| s.to_string()), stderr_tail.join("\n"))]
+    KilledBySignal {
+        signal: Option<i32>,
+        stderr_tail: Vec<String>,
+    },
+    #[error("ffmpeg exited unsuccessfully with code {}: {}", code.map_or_else(|| "unknown".to_string(), |c| c.to_string()), stderr_tail.join("\n"))]
+    ExitedUnsuccessfully {
+        code: Option<i32>,
+        /// The recognised cause, see [`classify_ffmpeg_failure`]
+        failure: Option<FfmpegFailure>,
+        stderr_tail: Vec<String>,
+    },
+}
+
+impl FfmpegError {
+    /// Trailing ffmpeg stderr attached to the error, empty if there was none
+    #[must_use]
+    pub fn stderr_tail(&self) -> &[String] {
+        match self {
+            Self::Cancelled { stderr_tail, .. }
+            | Self::TimedOut { stderr_tail, .. }
+            | Self::Stalled { stderr_tail, .. }
+            | Self::KilledBySignal { stderr_tail, .. }
+            | Self::ExitedUnsuccessfully { stderr_tail, .. } => stderr_tail,
+            _ => &[],
+        }
+    }
+
+    /// The classified cause of an unsuccessful ffmpeg run, if there was one
+    #[must_use]
+    pub fn failure(&self) -> Option<&FfmpegFailure> {
+        match self {
+            Self::ExitedUnsuccessfully { failure, .. } => failure.as_ref(),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn failure_kind(&self) -> Option<FfmpegFailureKind> {
+        self.failure().map(|failure| failure.kind)
+    }
+
+    /// Whether the same run could succeed if retried: stalls, and failures whose
+    /// [`FfmpegFailureKind::is_transient`]. Unclassified failures are assumed permanent
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Stalled { .. })
+            || self
+                .failure_kind()
+                .is_some_and(FfmpegFailureKind::is_transient)
+    }
+}
+
+/// Turn a stopped, killed or unsuccessful ffmpeg run into an [`FfmpegError`]. `stopped_by` is the
+/// shutdown step that ended the run, if cancellation ended it
+pub(crate) fn check_exit(
+    mut result: CommandExit,
+    stopped_by: Option<ShutdownAction>,
+) -> Result<CommandExit, FfmpegError> {
+    if stopped_by.is_some() {
+        tracing::debug!(stopped_by = ?stopped_by, "ffmpeg cancelled");
+        return Err(FfmpegError::Cancelled {
+            stopped_by,
+            stderr_tail: stderr_tail(&result.stderr_lines),
+        });
+    }
+
+    let Some(exit_code) = result.exit_code.take() else {
+        tracing::error!(
+            stdout_lines = result.stdout_lines.len(),
+            stderr_lines = result.stderr_lines.len(),
+            "Process returned but no exit status was present"
+        );
+        return Err(FfmpegError::IncompleteSubprocess { result });
+    };
+
+    if !exit_code.success {
+        // No code means the process didn't exit by itself
+        let error = match exit_code.code {
+            Some(code) => classify::failure_error(Some(code), &result.stderr_lines),
+            None => FfmpegError::KilledBySignal {
+                signal: None,
+                stderr_tail: stderr_tail(&result.stderr_lines),
+            },
+        };
+        tracing::error!(
+            exit_code = ?exit_code,
+            failure_kind = ?error.failure_kind(),
+            stderr_tail = ?error.stderr_tail(),
+            "ffmpeg exited unsuccessfully"
+        );
+        return Err(error);
+    }
+
+    result.exit_code = Some(exit_code);
+    Ok(result)
+}
+
+/// Run ffmpeg as an [`FfmpegJob`] with `options`, failing like [`FfmpegJob::wait`] unless it exits
+/// successfully: with [`FfmpegError::Cancelled`], [`FfmpegError::TimedOut`],
+/// [`FfmpegError::Stalled`], [`FfmpegError::KilledBySignal`] or
+/// [`FfmpegError::ExitedUnsuccessfully`]. stdout is kept in [`FfmpegExit::stdout_lines`]
+pub async fn ffmpeg<Prepare>(
+    options: &FfmpegJobOptions,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<FfmpegExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    ffmpeg_raw(options, cancellation_token, prepare)
+        .await?
+        .check_with(options)
+}
+
+/// [`ffmpeg`], returning the exit as is whether or not ffmpeg succeeded.
+///
+/// stderr is kept following [`FfmpegJobOptions::stderr_capture`] and emitted to tracing as
+/// [`crate::log::LogEvent`]s as it's read, stdout is kept as text lines. Progress is written to stderr
+/// (`-progress pipe:2`) for the stall watchdog and left out of both.
+///
+/// NOTE: This adds `-hide_banner -progress pipe:2 -loglevel level+<log_level>` to the BEGINNING of the `prepare`d command
+#[instrument(skip(options, prepare, cancellation_token))]
+pub async fn ffmpeg_raw<Prepare>(
+    options: &FfmpegJobOptions,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<FfmpegExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    FfmpegJob::spawn_with(options, JobStdout::Keep, None, cancellation_token, prepare)
+        .await?
+        .wait_raw()
+        .await
+}
+
+/// Run ffmpeg as an [`FfmpegJob`] with `options`, sending its progress to `tx`. Fails like
+/// [`ffmpeg`] unless ffmpeg exits successfully.
+///
+/// Progress is forwarded from the job's watch channel without waiting on `tx`, so a slow receiver
+/// misses blocks (those sent while its channel is full) rather than holding up ffmpeg or its
+/// shutdown.
+///
+/// NOTE: This adds `-hide_banner -progress pipe:1 -loglevel level+<log_level>` to the BEGINNING of the `prepare`d command
+#[tracing::instrument(
+    "libffmpeg::ffmpeg::progress",
+    skip(options, prepare, tx, cancellation_token)
+)]
+pub async fn ffmpeg_with_progress<Prepare>(
+    options: &FfmpegJobOptions,
+    tx: tokio::sync::mpsc::Sender<FfmpegProgress>,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<FfmpegExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    let job = FfmpegJob::spawn(options, cancellation_token, prepare).await?;
+
+    let forward = forward_progress(job.progress_stream(), tx);
+
+    let (result, ()) = tokio::join!(job.wait(), forward);
+    result
+}
+
+/// [`ffmpeg_with_progress`], sending [`ProgressEstimate`]s with completion and ETA against `expected`,
+/// the output duration (see [`Trim::apply`]). Like progress, estimates sent while `tx` is full are
+/// dropped.
+#[tracing::instrument(
+    "libffmpeg::ffmpeg::estimate",
+    skip(options, prepare, tx, cancellation_token)
+)]
+pub async fn ffmpeg_with_estimate<Prepare>(
+    options: &FfmpegJobOptions,
+    expected: Option<Duration>,
+    tx: tokio::sync::mpsc::Sender<ProgressEstimate>,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<FfmpegExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel::<FfmpegProgress>(100);
+
+    let run = ffmpeg_with_progress(options, progress_tx, cancellation_token, prepare);
+    let estimate = async {
+        let mut estimator = ProgressEstimator::new(expected);
+        while let Some(progress) = progress_rx.recv().await {
+            let estimate = estimator.update(progress);
+            tracing::trace!(
+                fraction = ?estimate.fraction,
+                eta = ?estimate.eta,
+                "Sending progress estimate"
+            );
+            if !send_progress(&tx, estimate) {
+                break;
+            }
+        }
+    };
+
+    let (result, ()) = tokio::join!(run, estimate);
+    result
+}
+
+/// [`ffmpeg_raw`], sending progress to `progress_tx` and forwarding every stderr line that isn't
+/// progress to `stderr_tx` as it arrives.
+///
+/// With `stdout_tx`, stdout is left to `prepare` (e.g. a `metadata=print:file='pipe\:1'` sink) and
+/// forwarded there line by line, otherwise it's kept like [`ffmpeg_raw`] does.
+///
+/// Returns once ffmpeg has exited and every line it wrote has been handled. Progress is sent like
+/// [`ffmpeg_with_progress`] does, sends to `stderr_tx` and `stdout_tx` are abandoned once
+/// `cancellation_token` is cancelled.
+pub(crate) async fn ffmpeg_monitored<Prepare>(
+    options: &FfmpegJobOptions,
+    progress_tx: Option<tokio::sync::mpsc::Sender<FfmpegProgress>>,
+    stderr_tx: Option<tokio::sync::mpsc::Sender<String>>,
+    stdout_tx: Option<tokio::sync::mpsc::Sender<String>>,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<FfmpegExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    let stdout = stdout_tx.map_or(JobStdout::Keep, JobStdout::Forward);
+    let job =
+        FfmpegJob::spawn_with(options, stdout, stderr_tx, cancellation_token, prepare).await?;
+
+    let forward = {
+        let progress = job.progress_stream();
+        async move {
+            if let Some(tx) = progress_tx {
+                forward_progress(progress, tx).await;
+            }
+        }
+    };
+
+    let (result, ()) = tokio::join!(job.wait_raw(), forward);
+    result
+}
+
+/// Forward a job's progress to `tx` until the job ends or the receiver is dropped, see
+/// [`send_progress`]
+async fn forward_progress(
+    progress: impl futures::Stream<Item = FfmpegProgress>,
+    tx: tokio::sync::mpsc::Sender<FfmpegProgress>,
+) {
+    let mut progress = std::pin::pin!(progress);
+    while let Some(progress) = progress.next().await {
+        if !send_progress(&tx, progress) {
+            break;
+        }
+    }
+}
+
+/// Send a progress update without waiting, dropping it if `tx` is full: a later update supersedes
+/// it, and a receiver that falls behind mustn't hold up ffmpeg. Returns `false` once the receiver
+/// has been dropped
+pub(crate) fn send_progress<T>(tx: &tokio::sync::mpsc::Sender<T>, progress: T) -> bool {
+    match tx.try_send(progress) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            tracing::trace!("Progress receiver is behind, dropping update");
+            true
+        }
+        Err(TrySendError::Closed(_)) => {
+            tracing::debug!("Progress receiver dropped");
+            false
+        }
+    }
+}
+
+/// Run ffmpeg as an [`FfmpegJob`], sending its progress to `tx` and asking it to quit with `q` on
+/// cancellation so outputs are finalized (see [`FfmpegJobOptions::shutdown`]). The same as
+/// [`ffmpeg_with_progress`], which runs as a job too.
+pub async fn ffmpeg_graceful_with_progress<Prepare>(
+    options: &FfmpegJobOptions,
+    tx: tokio::sync::mpsc::Sender<FfmpegProgress>,
+    cancellation_token: CancellationToken,
+    prepare: Prepare,
+) -> Result<FfmpegExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    ffmpeg_with_progress(options, tx, cancellation_token, prepare).await
+}
+
+/// How an [`ffmpeg_graceful`] run ended
+#[derive(Debug, Clone, Serialize, Deserialize, Valuable)]
+pub struct GracefulExit {
+    pub exit: CommandExit,
+    /// The shutdown step ffmpeg exited after, `None` if it exited by itself
+    pub stopped_by: Option<ShutdownAction>,
+}
+
+/// Run ffmpeg through a caller supplied monitor, stopping it with `policy` on cancellation. Fails
+/// like [`ffmpeg`] unless ffmpeg exits successfully, including with [`FfmpegError::Cancelled`] after
+/// a graceful stop. Use [`ffmpeg_graceful_raw`] to treat a graceful stop as success.
+///
+/// libcmd doesn't expose the process id, so this can't signal ffmpeg: [`ShutdownAction::Interrupt`]
+/// and [`ShutdownAction::Terminate`] steps are skipped, leaving [`ShutdownAction::Quit`] (sent
+/// through the monitor) and the final kill. Use [`FfmpegJob`] for the full escalation.
+pub async fn ffmpeg_graceful<Prepare>(
+    policy: &ShutdownPolicy,
+    cancellation_token: CancellationToken,
+    client: &mut CommandMonitorClient,
+    server: &mut CommandMonitorServer,
+    prepare: Prepare,
+) -> Result<GracefulExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    let GracefulExit { exit, stopped_by } =
+        ffmpeg_graceful_raw(policy, cancellation_token, client, server, prepare).await?;
+    let exit = check_exit(exit, stopped_by)?;
+    Ok(GracefulExit { exit, stopped_by })
+}
+
+/// [`ffmpeg_graceful`], returning the exit as is whether ffmpeg succeeded, failed or was stopped
+#[instrument(skip_all)]
+pub async fn ffmpeg_graceful_raw<Prepare>(
+    policy: &ShutdownPolicy,
+    cancellation_token: CancellationToken,
+    client: &mut CommandMonitorClient,
+    server: &mut CommandMonitorServer,
+    prepare: Prepare,
+) -> Result<GracefulExit, FfmpegError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    tracing::debug!("Starting ffmpeg execution");
+
+    let ffmpeg_path = find_binary_env("ffmpeg")
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, "Failed to search for ffmpeg binary"))?
+        .ok_or(FfmpegError::NotFound)
+        .inspect_err(
+            |e| tracing::error!(error =% e, error_context =? e, "ffmpeg binary not found"),
+        )?;
+
+    tracing::info!(
+        ffmpeg_path = %ffmpeg_path.display(),
+        "Executing ffmpeg"
+    );
+
+    // Different source token for the process, lets us gracefully exit
+    let process_token = CancellationToken::new();
+
+    // Cancelled after the process exits
+    let exit_token = CancellationToken::new();
+
+    // Flow:
+    //  1. If the process exits naturally before cancellation, do nothing and return early
+    //  2. User requests cancellation
+    //  3. Run the policy's steps in order: send "q" to ffmpeg's stdin and give it the step's timeout
+    //     to exit (wait using `exit_token`), skipping the signal steps libcmd can't deliver
+    //  4. If the process still hasn't exited once the steps run out (or at a Kill step), cancel the
+    //     process' token, signals that it should send SIGKILL
+    //  5. The process will be killed, as if none of this was ever here
+    // The task returns the action the process exited after, `None` if it exited by itself
+    let kill_handle = {
+        let client = client.clone();
+        let process_token = process_token.clone();
+        let exit_token = exit_token.clone();
+        let kill_token = cancellation_token.child_token();
+        let policy = policy.clone();
+        let span = tracing::debug_span!("libffmpeg::ffmpeg::graceful_kill");
+        tokio::spawn(
+            async move {
+                // Wait for kill token to cancel (user requested cancellation)
+                tokio::select! {
+                    // An exit that raced the cancellation wasn't caused by it
+                    biased;
+                    () = exit_token.cancelled() => {
+                        // if process exits before kill is requested, we don't want to kill the process
+                        return None;
+                    },
+                    () = kill_token.cancelled() => {
+                        // Continue killing the process
+                    }
+                }
+
+                for step in &policy.steps {
+                    match step.action {
+                        ShutdownAction::Kill => break,
+                        ShutdownAction::Interrupt | ShutdownAction::Terminate => {
+                            tracing::warn!(
+                                action = %step.action,
+                                "Can't signal ffmpeg run through libcmd, skipping shutdown step"
+                            );
+                            continue;
+                        }
+                        ShutdownAction::Quit => {}
+                    }
+
+                    tracing::debug!(timeout = ?step.timeout, "Cancellation requested, sending quit to ffmpeg");
+                    client.send("q").await;
+
+                    // Wait for exit to be cancelled (process exited), up to the step's timeout
+                    if tokio::time::timeout(step.timeout, exit_token.cancelled())
+                        .await
+                        .is_ok()
+                    {
+                        tracing::debug!("ffmpeg exited after quit");
+                        return Some(ShutdownAction::Quit);
+                    }
+                    tracing::debug!("ffmpeg still running after quit");
+                }
+
+                // Process didn't respond, tell the manager to kill the process
+                tracing::warn!("Killing ffmpeg");
+                process_token.cancel();
+                Some(ShutdownAction::Kill)
+            }
+            .instrument(span),
+        )
+    };
+
+    let result = libcmd::run(
+        ffmpeg_path,
+        Some(server.clone()),
+        process_token.child_token(),
+        prepare,
+    )
+    .await
+    .inspect(|exit| {
+        tracing::debug!(exit = exit.as_value(), "ffmpeg completed");
+    })
+    .inspect_err(|e| {
+        tracing::error!(
+            error = %e,
+            "ffmpeg execution failed"
+        );
+    })
+    .map_err(FfmpegError::from);
+
+    exit_token.cancel();
+
+    let stopped_by = kill_handle
+        .await
+        .inspect_err(|e| {
+            tracing::error!(error=%e, error_context=?e,"Failed to wait for kill handle to exit");
+        })
+        .unwrap_or_default();
+
+    Ok(GracefulExit {
+        exit: result?,
+        stopped_by,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_is_dropped_rather_than_waited_on() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let block = |frame| FfmpegProgress {
+            frame: Some(frame),
+            ..FfmpegProgress::default()
+        };
+
+        assert!(send_progress(&tx, block(1)));
+        // The receiver is behind, the update is dropped without blocking
+        assert!(send_progress(&tx, block(2)));
+        assert_eq!(rx.try_recv().unwrap().frame, Some(1));
+        assert!(rx.try_recv().is_err());
+
+        drop(rx);
+        assert!(!send_progress(&tx, block(3)));
+    }
+}
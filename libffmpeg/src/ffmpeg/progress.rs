@@ -0,0 +1,295 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use valuable::Valuable;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    Valuable,
+    Display,
+    EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressState {
+    #[default]
+    Continue,
+    /// The last block ffmpeg writes
+    End,
+}
+
+/// A `stream_<file>_<stream>_q` value, the quantizer of an output video stream
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct StreamQuality {
+    pub file_index: u32,
+    pub stream_index: u32,
+    pub q: f64,
+}
+
+/// One block of `-progress` output. Values ffmpeg reports as `N/A` (or doesn't report, e.g. `frame`
+/// for audio only outputs) are `None`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FfmpegProgress {
+    pub frame: Option<u64>,
+    pub fps: Option<f64>,
+    pub stream_quality: Vec<StreamQuality>,
+    pub bitrate_kbps: Option<f64>,
+    /// Bytes written so far
+    pub total_size: Option<u64>,
+    /// Position in the output
+    pub out_time: Option<Duration>,
+    pub dup_frames: Option<u64>,
+    pub drop_frames: Option<u64>,
+    /// Processing speed as a multiple of realtime
+    pub speed: Option<f64>,
+    pub state: ProgressState,
+}
+
+/// Collects `key=value` lines into an [`FfmpegProgress`], completing it on `progress=`
+#[derive(Debug, Default)]
+pub(crate) struct ProgressParser {
+    current: FfmpegProgress,
+}
+
+impl ProgressParser {
+    pub(crate) fn push(&mut self, line: &str) -> Option<FfmpegProgress> {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            tracing::trace!(line = %line, "Progress line missing '=' separator");
+            return None;
+        };
+        let value = value.trim();
+        let current = &mut self.current;
+
+        match key {
+            "frame" => current.frame = value.parse().ok(),
+            "fps" => current.fps = value.parse().ok(),
+            "bitrate" => {
+                current.bitrate_kbps = value
+                    .strip_suffix("kbits/s")
+                    .and_then(|value| value.trim().parse().ok());
+            }
+            "total_size" => current.total_size = value.parse().ok(),
+            // out_time_ms is also in microseconds, out_time is the same value formatted
+            "out_time_us" => {
+                current.out_time = value
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(|us| u64::try_from(us).ok())
+                    .map(Duration::from_micros);
+            }
+            "dup_frames" => current.dup_frames = value.parse().ok(),
+            "drop_frames" => current.drop_frames = value.parse().ok(),
+            "speed" => {
+                current.speed = value
+                    .strip_suffix('x')
+                    .and_then(|value| value.trim().parse().ok());
+            }
+            "progress" => {
+                current.state = if value == "end" {
+                    ProgressState::End
+                } else {
+                    ProgressState::Continue
+                };
+                return Some(std::mem::take(current));
+            }
+            _ => {
+                if let Some(quality) = parse_stream_quality(key, value) {
+                    current.stream_quality.push(quality);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Whether `line` belongs to a `-progress` block rather than ffmpeg's log, for progress written to
+/// stderr. Progress lines are a single `key=value` with one of the keys ffmpeg writes, log lines
+/// that happen to contain `=` (like `-stats`' `frame=  240 fps= 48 ...`) don't match
+pub(crate) fn is_progress_line(line: &str) -> bool {
+    let Some((key, value)) = line.split_once('=') else {
+        return false;
+    };
+    if value.contains('=') {
+        return false;
+    }
+    matches!(
+        key,
+        "frame"
+            | "fps"
+            | "bitrate"
+            | "total_size"
+            | "out_time_us"
+            | "out_time_ms"
+            | "out_time"
+            | "dup_frames"
+            | "drop_frames"
+            | "speed"
+            | "progress"
+    ) || key.strip_prefix("stream_").is_some_and(|rest| {
+        !rest.is_empty()
+            && rest
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+    })
+}
+
+fn parse_stream_quality(key: &str, value: &str) -> Option<StreamQuality> {
+    let (file_index, stream_index) = key
+        .strip_prefix("stream_")?
+        .strip_suffix("_q")?
+        .split_once('_')?;
+    Some(StreamQuality {
+        file_index: file_index.parse().ok()?,
+        stream_index: stream_index.parse().ok()?,
+        q: value.parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two blocks of `-progress pipe:1` from an x264 encode
+    const OUTPUT: &str = "\
+frame=240
+fps=47.91
+stream_0_0_q=28.0
+bitrate=1520.4kbits/s
+total_size=1900544
+out_time_us=10000000
+out_time_ms=10000000
+out_time=00:00:10.000000
+dup_frames=0
+drop_frames=0
+speed=1.99x
+progress=continue
+frame=721
+fps=48.05
+stream_0_0_q=-1.0
+bitrate=1498.7kbits/s
+total_size=5626880
+out_time_us=30033333
+out_time_ms=30033333
+out_time=00:00:30.033333
+dup_frames=2
+drop_frames=0
+speed=2.0x
+progress=end";
+
+    fn parse(output: &str) -> Vec<FfmpegProgress> {
+        let mut parser = ProgressParser::default();
+        output
+            .lines()
+            .filter_map(|line| parser.push(line))
+            .collect()
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let blocks = parse(OUTPUT);
+        assert_eq!(
+            blocks,
+            vec![
+                FfmpegProgress {
+                    frame: Some(240),
+                    fps: Some(47.91),
+                    stream_quality: vec![StreamQuality {
+                        file_index: 0,
+                        stream_index: 0,
+                        q: 28.0,
+                    }],
+                    bitrate_kbps: Some(1520.4),
+                    total_size: Some(1_900_544),
+                    out_time: Some(Duration::from_secs(10)),
+                    dup_frames: Some(0),
+                    drop_frames: Some(0),
+                    speed: Some(1.99),
+                    state: ProgressState::Continue,
+                },
+                FfmpegProgress {
+                    frame: Some(721),
+                    fps: Some(48.05),
+                    stream_quality: vec![StreamQuality {
+                        file_index: 0,
+                        stream_index: 0,
+                        q: -1.0,
+                    }],
+                    bitrate_kbps: Some(1498.7),
+                    total_size: Some(5_626_880),
+                    out_time: Some(Duration::from_micros(30_033_333)),
+                    dup_frames: Some(2),
+                    drop_frames: Some(0),
+                    speed: Some(2.0),
+                    state: ProgressState::End,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn not_available_values_are_none() {
+        // The first block of an audio only output, before anything has been written
+        let blocks = parse(
+            "\
+bitrate=N/A
+total_size=N/A
+out_time_us=N/A
+out_time_ms=N/A
+out_time=N/A
+dup_frames=0
+drop_frames=0
+speed=N/A
+progress=continue",
+        );
+        assert_eq!(
+            blocks,
+            vec![FfmpegProgress {
+                dup_frames: Some(0),
+                drop_frames: Some(0),
+                ..FfmpegProgress::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn negative_out_time_is_none() {
+        let blocks = parse("out_time_us=-23220\nprogress=continue");
+        assert_eq!(blocks[0].out_time, None);
+    }
+
+    #[test]
+    fn ignores_lines_without_a_separator() {
+        let mut parser = ProgressParser::default();
+        assert_eq!(parser.push("[error] Conversion failed!"), None);
+        assert_eq!(parser.push(""), None);
+        assert_eq!(
+            parser.push("progress=end").map(|progress| progress.state),
+            Some(ProgressState::End)
+        );
+    }
+
+    #[test]
+    fn tells_progress_lines_from_log_lines() {
+        assert!(OUTPUT.lines().all(is_progress_line));
+        assert!(is_progress_line("stream_0_0_psnr_y=42.1"));
+        for line in [
+            "[info] Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'input.mp4':",
+            "[Parsed_loudnorm_0 @ 0x600003a1c000] ",
+            "\t\"input_i\" : \"-23.05\",",
+            "    I:         -23.0 LUFS",
+            "frame=  240 fps= 48 q=28.0 size=    1856KiB time=00:00:10.00 bitrate=1520.4kbits/s",
+            "lavfi.signalstats.YAVG=16.023",
+            "stream_=1",
+        ] {
+            assert!(!is_progress_line(line), "{line}");
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::{process::ExitStatus, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, ChildStdin},
+};
+use valuable::Valuable;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownAction {
+    /// Send `q` to ffmpeg's stdin, it stops reading input and finalizes its outputs
+    Quit,
+    /// SIGINT, handled by ffmpeg like `q`. Unix only, skipped elsewhere
+    Interrupt,
+    /// SIGTERM, handled by ffmpeg like `q`. Unix only, skipped elsewhere
+    Terminate,
+    /// SIGKILL, outputs are left however they were when ffmpeg died
+    Kill,
+}
+
+/// Why a job was shut down
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Valuable, Display, EnumString,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// The job's cancellation token was cancelled, or the job dropped
+    Cancelled,
+    /// [`super::FfmpegJobOptions::timeout`] elapsed
+    TimedOut,
+    /// No progress within [`super::FfmpegJobOptions::stall_timeout`]
+    Stalled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShutdownStep {
+    pub action: ShutdownAction,
+    /// How long ffmpeg gets to exit before moving on to the next step
+    pub timeout: Duration,
+}
+
+/// What to do when a running job is stopped (see [`StopReason`]): each step is tried in order until ffmpeg exits.
+/// ffmpeg is always killed once the steps run out, so a [`ShutdownAction::Kill`] step is only needed
+/// to cut the list short.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShutdownPolicy {
+    pub steps: Vec<ShutdownStep>,
+}
+
+impl Default for ShutdownPolicy {
+    /// `q`, then kill after 5 seconds
+    fn default() -> Self {
+        Self::kill().then(ShutdownAction::Quit, Duration::from_secs(5))
+    }
+}
+
+impl ShutdownPolicy {
+    /// Kill straight away, without any steps
+    #[must_use]
+    pub fn kill() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    /// Add a step, run after the ones already added
+    #[must_use]
+    pub fn then(mut self, action: ShutdownAction, timeout: Duration) -> Self {
+        self.steps.push(ShutdownStep { action, timeout });
+        self
+    }
+}
+
+/// Stop `child` following `policy`, returning its exit status and the action it stopped after
+pub(crate) async fn shut_down(
+    child: &mut Child,
+    stdin: &mut Option<ChildStdin>,
+    policy: &ShutdownPolicy,
+) -> (std::io::Result<ExitStatus>, ShutdownAction) {
+    for step in &policy.steps {
+        if step.action == ShutdownAction::Kill {
+            break;
+        }
+
+        tracing::debug!(action = %step.action, timeout = ?step.timeout, "Running shutdown step");
+        let delivered = match step.action {
+            ShutdownAction::Quit => send_quit(stdin).await,
+            ShutdownAction::Interrupt => send_signal(child, Signal::Interrupt),
+            ShutdownAction::Terminate => send_signal(child, Signal::Terminate),
+            ShutdownAction::Kill => unreachable!("handled above"),
+        };
+        if !delivered {
+            continue;
+        }
+
+        if let Ok(status) = tokio::time::timeout(step.timeout, child.wait()).await {
+            tracing::debug!(action = %step.action, "ffmpeg exited after shutdown step");
+            return (status, step.action);
+        }
+        tracing::debug!(action = %step.action, "ffmpeg still running after shutdown step");
+    }
+
+    tracing::warn!("Killing ffmpeg");
+    let _ = child.start_kill().inspect_err(|e| {
+        tracing::warn!(error = %e, "Failed to kill ffmpeg");
+    });
+    (child.wait().await, ShutdownAction::Kill)
+}
+
+async fn send_quit(stdin: &mut Option<ChildStdin>) -> bool {
+    let Some(stdin) = stdin else {
+        tracing::warn!("ffmpeg's stdin isn't available, can't send quit");
+        return false;
+    };
+    let sent = async {
+        stdin.write_all(b"q").await?;
+        stdin.flush().await
+    };
+    sent.await
+        .inspect_err(|e| tracing::warn!(error = %e, "Failed to send quit to ffmpeg"))
+        .is_ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: Signal) -> bool {
+    // Once a child is reaped its pid can be reused by an unrelated process, so only signal one
+    // that's still running. Exited children are reaped here, waiting on them returns straight away
+    match child.try_wait() {
+        Ok(None) => {}
+        Ok(Some(_)) => return true,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to check whether ffmpeg is still running");
+            return false;
+        }
+    }
+    let Some(pid) = child.id() else {
+        return true;
+    };
+    let signal = match signal {
+        Signal::Interrupt => libc::SIGINT,
+        Signal::Terminate => libc::SIGTERM,
+    };
+
+    // SAFETY: kill(2) doesn't touch our memory. The pid still belongs to `child`: try_wait just saw
+    // it running, and nothing but `child` reaps it (we hold it mutably), so if it has exited since
+    // it's a zombie that keeps the pid until it's waited on
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        true
+    } else {
+        let e = std::io::Error::last_os_error();
+        tracing::warn!(error = %e, signal, "Failed to signal ffmpeg");
+        false
+    }
+}
+
+#[cfg(not(unix))]
+fn send_signal(_child: &mut Child, signal: Signal) -> bool {
+    tracing::warn!(signal = ?signal, "Signals aren't supported on this platform, skipping");
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::Stdio;
+
+    use tokio::process::Command;
+
+    use super::*;
+
+    fn sleep() -> Child {
+        Command::new("sleep")
+            .arg("30")
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn escalates_to_the_first_step_that_works() {
+        let mut child = sleep();
+        // No stdin to quit through, so the policy moves on to SIGTERM
+        let policy = ShutdownPolicy::kill()
+            .then(ShutdownAction::Quit, Duration::from_secs(5))
+            .then(ShutdownAction::Terminate, Duration::from_secs(5));
+        let (status, action) = shut_down(&mut child, &mut None, &policy).await;
+        assert_eq!(action, ShutdownAction::Terminate);
+        assert_eq!(
+            std::os::unix::process::ExitStatusExt::signal(&status.unwrap()),
+            Some(libc::SIGTERM)
+        );
+    }
+
+    #[tokio::test]
+    async fn kills_once_the_steps_run_out() {
+        let mut child = sleep();
+        let (status, action) = shut_down(&mut child, &mut None, &ShutdownPolicy::kill()).await;
+        assert_eq!(action, ShutdownAction::Kill);
+        assert_eq!(
+            std::os::unix::process::ExitStatusExt::signal(&status.unwrap()),
+            Some(libc::SIGKILL)
+        );
+    }
+
+    #[tokio::test]
+    async fn exited_child_is_not_signalled() {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().await.unwrap();
+        assert!(send_signal(&mut child, Signal::Terminate));
+    }
+}
@@ -1,3 +1,6 @@
+pub mod analysis;
 pub mod duration;
 pub mod env;
 pub mod ffmpeg;
+pub mod log;
+pub(crate) mod process;
@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use valuable::Valuable;
+
+/// ffmpeg/ffprobe log levels, as accepted by `-v`/`-loglevel`
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    Valuable,
+    Display,
+    EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Quiet,
+    Panic,
+    Fatal,
+    Error,
+    Warning,
+    Info,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+/// Target ffmpeg's log events are emitted under, see [`LogEvent::emit`]
+pub const LOG_TARGET: &str = "libffmpeg::ffmpeg::log";
+
+/// One line of ffmpeg's log output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+pub struct LogEvent {
+    /// `None` unless ffmpeg was run with `-loglevel level+...`, and for continuation lines
+    pub level: Option<LogLevel>,
+    /// The context the line was logged from, e.g. `libx264 @ 0x7f8b5c00`
+    pub component: Option<String>,
+    pub message: String,
+}
+
+impl LogEvent {
+    #[must_use]
+    pub fn parse(line: &str) -> Self {
+        let (component, level, message) = split_log_tags(line);
+        Self {
+            level,
+            component: component.map(ToString::to_string),
+            message: message.to_string(),
+        }
+    }
+
+    /// [`LogEvent::component`] without the instance address, e.g. `libx264`
+    #[must_use]
+    pub fn component_name(&self) -> Option<&str> {
+        self.component.as_deref().map(component_name)
+    }
+
+    /// Emit as a tracing event under [`LOG_TARGET`] at the matching level. Lines without a level
+    /// are emitted at trace
+    pub fn emit(&self) {
+        let component = self.component.as_deref().unwrap_or_default();
+        match self.level {
+            Some(LogLevel::Panic | LogLevel::Fatal | LogLevel::Error) => {
+                tracing::error!(target: LOG_TARGET, component, "{}", self.message);
+            }
+            Some(LogLevel::Warning) => {
+                tracing::warn!(target: LOG_TARGET, component, "{}", self.message);
+            }
+            Some(LogLevel::Info) => {
+                tracing::info!(target: LOG_TARGET, component, "{}", self.message);
+            }
+            Some(LogLevel::Verbose | LogLevel::Debug) => {
+                tracing::debug!(target: LOG_TARGET, component, "{}", self.message);
+            }
+            Some(LogLevel::Quiet | LogLevel::Trace) | None => {
+                tracing::trace!(target: LOG_TARGET, component, "{}", self.message);
+            }
+        }
+    }
+}
+
+/// Split the `[component @ 0x...]` and `[level]` (with `-loglevel level+...`) prefixes off an
+/// ffmpeg log line, returning the component's name, level and message
+pub(crate) fn split_log_prefixes(line: &str) -> (Option<&str>, Option<LogLevel>, &str) {
+    let (component, level, message) = split_log_tags(line);
+    (component.map(component_name), level, message)
+}
+
+/// [`split_log_prefixes`], keeping the component's address
+fn split_log_tags(line: &str) -> (Option<&str>, Option<LogLevel>, &str) {
+    let mut component = None;
+    let mut level = None;
+    let mut rest = line.trim_end();
+
+    while let Some((tag, after)) = rest
+        .strip_prefix('[')
+        .and_then(|tagged| tagged.split_once(']'))
+    {
+        if let Ok(parsed) = tag.parse::<LogLevel>() {
+            level = Some(parsed);
+        } else if component.is_none() && level.is_none() {
+            component = Some(tag);
+        } else {
+            break;
+        }
+        rest = after.trim_start();
+    }
+
+    (component, level, rest)
+}
+
+fn component_name(component: &str) -> &str {
+    component
+        .split_once(" @ ")
+        .map_or(component, |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_component_and_level() {
+        let event = LogEvent::parse(
+            "[libx264 @ 0x55f1c8a4e940] [error] Error setting option crf to value fast.",
+        );
+        assert_eq!(
+            event,
+            LogEvent {
+                level: Some(LogLevel::Error),
+                component: Some("libx264 @ 0x55f1c8a4e940".to_string()),
+                message: "Error setting option crf to value fast.".to_string(),
+            }
+        );
+        assert_eq!(event.component_name(), Some("libx264"));
+    }
+
+    #[test]
+    fn parses_component_without_a_level() {
+        let event = LogEvent::parse("[Parsed_cropdetect_0 @ 0x600003a1c000] x1:0 x2:1919 y1:140\n");
+        assert_eq!(
+            (event.level, event.component_name(), event.message.as_str()),
+            (None, Some("Parsed_cropdetect_0"), "x1:0 x2:1919 y1:140")
+        );
+    }
+
+    #[test]
+    fn parses_level_without_a_component() {
+        let event = LogEvent::parse("[warning] Guessed Channel Layout: stereo");
+        assert_eq!(
+            (event.level, event.component, event.message.as_str()),
+            (
+                Some(LogLevel::Warning),
+                None,
+                "Guessed Channel Layout: stereo"
+            )
+        );
+    }
+
+    #[test]
+    fn parses_lines_without_a_level_or_component() {
+        for line in [
+            "  Stream #0:0[0x1](und): Video: h264 (High)",
+            "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'input.mp4':",
+            "    I:         -23.0 LUFS",
+        ] {
+            let event = LogEvent::parse(line);
+            assert_eq!(
+                (event.level, event.component, event.message.as_str()),
+                (None, None, line)
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_brackets_in_the_message() {
+        assert_eq!(
+            split_log_prefixes("[h264 @ 0x55c1e0d3a140] [error] [mmco] unref short failure"),
+            (
+                Some("h264"),
+                Some(LogLevel::Error),
+                "[mmco] unref short failure"
+            )
+        );
+        assert_eq!(
+            split_log_prefixes("[info] [q] to stop"),
+            (None, Some(LogLevel::Info), "[q] to stop")
+        );
+        assert_eq!(
+            split_log_prefixes("[out#0/mp4 @ 0x5607a3e5c1c0] video:1024KiB audio:96KiB"),
+            (Some("out#0/mp4"), None, "video:1024KiB audio:96KiB")
+        );
+    }
+
+    #[test]
+    fn unterminated_tag_is_message() {
+        assert_eq!(
+            split_log_prefixes("[libx264 @ 0x55f1c8a4e940 frame I:1"),
+            (None, None, "[libx264 @ 0x55f1c8a4e940 frame I:1")
+        );
+    }
+}
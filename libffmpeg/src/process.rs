@@ -0,0 +1,113 @@
+//! ffmpeg/ffprobe spawned with tokio directly, for the runs `libcmd::run` can't serve.
+//!
+//! libcmd owns the child for the whole run: it doesn't expose the pid (so a job can't be signalled
+//! or shut down step by step), its monitor only writes text lines to stdin (piped probes stream
+//! arbitrary bytes), it splits stdout into lossy text lines (frame hashing reads rawvideo), and it
+//! keeps every line of output until the process exits (capture policies bound that). Extending it
+//! for all of those would mean handing out the [`Child`], which is what this does.
+
+use std::{
+    path::PathBuf,
+    process::{ExitStatus, Stdio},
+};
+
+use liberror::AnyError;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::{Child, Command},
+};
+
+use crate::{
+    duration::DurationError,
+    env::find::{FindBinaryError, find_binary_env},
+    ffmpeg::{
+        FfmpegError,
+        shutdown::{ShutdownPolicy, shut_down},
+    },
+};
+
+/// Why [`spawn`] couldn't start the binary, converted into the caller's error type
+#[derive(Debug)]
+pub(crate) enum SpawnError {
+    FindBinary(FindBinaryError),
+    NotFound,
+    Spawn(AnyError),
+}
+
+impl From<FindBinaryError> for SpawnError {
+    fn from(value: FindBinaryError) -> Self {
+        Self::FindBinary(value)
+    }
+}
+
+impl From<SpawnError> for FfmpegError {
+    fn from(value: SpawnError) -> Self {
+        match value {
+            SpawnError::FindBinary(inner_error) => Self::FindBinary { inner_error },
+            SpawnError::NotFound => Self::NotFound,
+            SpawnError::Spawn(inner_error) => Self::Spawn { inner_error },
+        }
+    }
+}
+
+impl From<SpawnError> for DurationError {
+    fn from(value: SpawnError) -> Self {
+        match value {
+            SpawnError::FindBinary(inner_error) => Self::FindBinary { inner_error },
+            SpawnError::NotFound => Self::FfprobeNotFound,
+            SpawnError::Spawn(inner_error) => Self::Spawn { inner_error },
+        }
+    }
+}
+
+/// Find `binary` and spawn it with stdout and stderr piped, `stdin` as given, returning the child and
+/// the binary's path. The child is killed if it's dropped before it exits, so cancelling the caller's
+/// future can't leave it behind
+pub(crate) async fn spawn<Prepare>(
+    binary: &str,
+    stdin: Stdio,
+    prepare: Prepare,
+) -> Result<(Child, PathBuf), SpawnError>
+where
+    Prepare: FnOnce(&mut Command),
+{
+    let Some(path) = find_binary_env(binary).await.inspect_err(|e| {
+        tracing::error!(error = %e, binary, "Failed to search for binary");
+    })?
+    else {
+        tracing::error!(binary, "Binary not found");
+        return Err(SpawnError::NotFound);
+    };
+
+    let mut cmd = Command::new(&path);
+    prepare(&mut cmd);
+    cmd.stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let child = cmd
+        .spawn()
+        .inspect_err(|e| tracing::error!(error = %e, binary, "Failed to spawn"))
+        .map_err(|e| SpawnError::Spawn(e.into()))?;
+    Ok((child, path))
+}
+
+/// Kill `child` and reap it, for runs abandoned part way
+pub(crate) async fn kill(child: &mut Child) -> std::io::Result<ExitStatus> {
+    let (status, _) = shut_down(child, &mut None, &ShutdownPolicy::kill()).await;
+    status
+}
+
+/// Everything `reader` produces, split into lines. Invalid UTF-8 is replaced rather than failing,
+/// ffmpeg echoes file names and metadata as is
+pub(crate) async fn read_lines<R: AsyncRead + Unpin>(mut reader: R) -> Vec<String> {
+    let mut buffer = Vec::new();
+    let _ = reader.read_to_end(&mut buffer).await.inspect_err(|e| {
+        tracing::warn!(error = %e, "Failed to read process output");
+    });
+    String::from_utf8_lossy(&buffer)
+        .lines()
+        .map(ToString::to_string)
+        .collect()
+}